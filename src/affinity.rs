@@ -0,0 +1,168 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, Context, Result};
+use nix::sched::{sched_getaffinity, sched_setaffinity, CpuSet};
+use nix::unistd::Pid;
+
+use crate::parser::DBSArgs;
+
+/// The ids of `--vmm-affinity` and `--vcpu-affinity`, in the errors
+const HOST_CPU: &str = "host CPU";
+
+/// The host CPUs the VMM and vcpu threads are pinned to.
+#[derive(Clone, Debug, Default)]
+pub struct CpuAffinity {
+    /// CPUs of the VMM threads, from `--vmm-affinity`
+    vmm: Option<Vec<usize>>,
+    /// Host CPU of each vcpu, from `--vcpu-affinity`
+    vcpus: BTreeMap<u8, usize>,
+}
+
+impl CpuAffinity {
+    pub fn from_args(args: &DBSArgs) -> Result<Self> {
+        let create_args = &args.create_args;
+        let vmm = match &create_args.vmm_affinity {
+            Some(list) => Some(parse_id_list(list, HOST_CPU).context("Invalid vmm_affinity")?),
+            None => None,
+        };
+        let vcpus = match &create_args.vcpu_affinity {
+            Some(pins) => parse_vcpu_affinity(pins).context("Invalid vcpu_affinity")?,
+            None => BTreeMap::new(),
+        };
+
+        Ok(CpuAffinity { vmm, vcpus })
+    }
+
+    /// All the host CPUs used by the affinities.
+    pub fn host_cpus(&self) -> impl Iterator<Item = usize> + '_ {
+        self.vmm
+            .iter()
+            .flatten()
+            .chain(self.vcpus.values())
+            .copied()
+    }
+
+    /// The host CPUs the vcpus are pinned to.
+    pub fn vcpu_host_cpus(&self) -> impl Iterator<Item = usize> + '_ {
+        self.vcpus.values().copied()
+    }
+
+    /// The ids of the vcpus that are pinned.
+    pub fn vcpu_ids(&self) -> impl Iterator<Item = u8> + '_ {
+        self.vcpus.keys().copied()
+    }
+
+    /// Pin the calling thread to the VMM CPUs. The threads it spawns later,
+    /// i.e. the VMM, api server and vcpu threads, start with the same CPUs.
+    pub fn pin_vmm(&self) -> Result<()> {
+        if let Some(cpus) = &self.vmm {
+            sched_setaffinity(Pid::from_raw(0), &cpu_set(cpus)?)
+                .context("Failed to set the CPU affinity of the VMM")?;
+        }
+        Ok(())
+    }
+
+    /// Pin the vcpu threads, `(vcpu id, tid)`, to their host CPU. The vcpus
+    /// without one keep the CPUs of the VMM.
+    pub fn pin_vcpus(&self, tids: &[(u8, u32)]) -> Result<()> {
+        for (vcpu_id, tid) in tids.iter() {
+            if let Some(cpu) = self.vcpus.get(vcpu_id) {
+                sched_setaffinity(Pid::from_raw(*tid as i32), &cpu_set(&[*cpu])?)
+                    .with_context(|| format!("Failed to pin vcpu {vcpu_id} to CPU {cpu}"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The CPUs dbs-cli is allowed to run on.
+pub fn available_cpus() -> Result<CpuSet> {
+    sched_getaffinity(Pid::from_raw(0)).context("Failed to get the CPU affinity of dbs-cli")
+}
+
+fn cpu_set(cpus: &[usize]) -> Result<CpuSet> {
+    let mut cpu_set = CpuSet::new();
+    for cpu in cpus.iter() {
+        cpu_set
+            .set(*cpu)
+            .map_err(|_| anyhow!("CPU {cpu} is out of range"))?;
+    }
+    Ok(cpu_set)
+}
+
+/// Parse a list of ids such as `0-1,4`, in the format of the CPU and NUMA
+/// node lists of sysfs. `name` is the kind of ids, for the errors.
+pub fn parse_id_list(list: &str, name: &str) -> Result<Vec<usize>> {
+    let mut ids = vec![];
+    for range in list.split(',') {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let first = parse_id(first, name)?;
+        let last = parse_id(last, name)?;
+        if first > last {
+            return Err(anyhow!("invalid {name} range {range:?}"));
+        }
+        ids.extend(first..=last);
+    }
+    Ok(ids)
+}
+
+/// Parse `<vcpu>:<cpu>` pairs such as `0:2,1:3`.
+fn parse_vcpu_affinity(pins: &str) -> Result<BTreeMap<u8, usize>> {
+    let mut vcpus = BTreeMap::new();
+    for pin in pins.split(',') {
+        let (vcpu, cpu) = pin
+            .split_once(':')
+            .ok_or_else(|| anyhow!("{pin:?} should be <vcpu>:<host cpu>"))?;
+        let vcpu: u8 = vcpu
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("invalid vcpu id {vcpu:?}"))?;
+        if vcpus.insert(vcpu, parse_id(cpu, HOST_CPU)?).is_some() {
+            return Err(anyhow!("vcpu {vcpu} is pinned more than once"));
+        }
+    }
+    Ok(vcpus)
+}
+
+fn parse_id(id: &str, name: &str) -> Result<usize> {
+    id.trim()
+        .parse()
+        .map_err(|_| anyhow!("invalid {name} {id:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_id_list() {
+        assert_eq!(parse_id_list("3", HOST_CPU).unwrap(), vec![3]);
+        assert_eq!(parse_id_list("0-2,5", HOST_CPU).unwrap(), vec![0, 1, 2, 5]);
+        assert_eq!(parse_id_list("4, 1-1", HOST_CPU).unwrap(), vec![4, 1]);
+
+        let error = |list: &str| parse_id_list(list, "NUMA node").unwrap_err().to_string();
+        assert_eq!(error(""), "invalid NUMA node \"\"");
+        assert_eq!(error("0,x"), "invalid NUMA node \"x\"");
+        assert_eq!(error("3-1"), "invalid NUMA node range \"3-1\"");
+        assert_eq!(error("1-"), "invalid NUMA node \"\"");
+        assert_eq!(error("-1"), "invalid NUMA node \"\"");
+    }
+
+    #[test]
+    fn test_parse_vcpu_affinity() {
+        let vcpus = parse_vcpu_affinity("0:2,1:3, 4:0").unwrap();
+        assert_eq!(
+            vcpus.into_iter().collect::<Vec<_>>(),
+            vec![(0, 2), (1, 3), (4, 0)]
+        );
+
+        let error = |pins: &str| parse_vcpu_affinity(pins).unwrap_err().to_string();
+        assert_eq!(error("0"), "\"0\" should be <vcpu>:<host cpu>");
+        assert_eq!(error("256:1"), "invalid vcpu id \"256\"");
+        assert_eq!(error("0:a"), "invalid host CPU \"a\"");
+        assert_eq!(error("0:1,0:2"), "vcpu 0 is pinned more than once");
+    }
+}
@@ -1,20 +1,36 @@
 // Copyright (C) 2022 Alibaba Cloud. All rights reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::io::Write;
+use std::io::{Read, Write};
+use std::net::Shutdown;
 use std::os::unix::net::UnixStream;
 
 use anyhow::{Context, Result};
 use serde_json::{json, Value};
 
+use crate::cgroup::CgroupLimits;
+use crate::error::{Error, ErrorReply};
 use crate::parser::DBSArgs;
 
 pub fn run_api_client(args: DBSArgs) -> Result<()> {
     let request;
     if let Some(vcpu_resize_num) = args.update_args.vcpu_resize {
         request = request_cpu_resize(vcpu_resize_num);
-        send_request(request, args.api_sock_path)?;
+        send_request(request, args.api_sock_path.clone())?;
     }
+    let limits = CgroupLimits::from_args(&args);
+    if !limits.is_empty() {
+        send_request(request_cgroup_update(&limits)?, args.api_sock_path)?;
+    }
+
+    Ok(())
+}
+
+pub fn run_status_client(args: DBSArgs) -> Result<()> {
+    let response = send_request(request_status(), args.api_sock_path)?;
+    let status: Value =
+        serde_json::from_str(&response).context("Failed at parsing the api server response")?;
+    println!("{}", serde_json::to_string_pretty(&status)?);
 
     Ok(())
 }
@@ -26,12 +42,45 @@ fn request_cpu_resize(vcpu_resize_num: usize) -> Value {
     })
 }
 
-fn send_request(request: Value, api_sock_path: String) -> Result<()> {
-    let mut unix_stream = UnixStream::connect(api_sock_path).context("Could not create stream")?;
+fn request_cgroup_update(limits: &CgroupLimits) -> Result<Value> {
+    let mut request = serde_json::to_value(limits)?;
+    request["action"] = Value::from("update_cgroup");
+    Ok(request)
+}
+
+fn request_status() -> Value {
+    json!({
+        "action": "get_status",
+    })
+}
+
+/// Send a request to the api server and return its reply, which is empty for
+/// actions that do not produce any data. A failed request is returned as the
+/// error replied by the server.
+fn send_request(request: Value, api_sock_path: String) -> Result<String> {
+    let mut unix_stream = UnixStream::connect(&api_sock_path).map_err(|e| {
+        Error::Transport(format!(
+            "Could not connect to the api server {api_sock_path:?}: {e}"
+        ))
+    })?;
 
     unix_stream
         .write(request.to_string().as_bytes()) // we write bytes, &[u8]
-        .context("Failed at writing onto the unix stream")?;
+        .map_err(|e| Error::Transport(format!("Failed at writing onto the unix stream: {e}")))?;
+    // the server reads the request until EOF, so close our writing half
+    unix_stream.shutdown(Shutdown::Write).map_err(|e| {
+        Error::Transport(format!(
+            "Failed at shutting down the writing half of the unix stream: {e}"
+        ))
+    })?;
 
-    Ok(())
+    let mut response = String::new();
+    unix_stream
+        .read_to_string(&mut response)
+        .map_err(|e| Error::Transport(format!("Failed at reading the unix stream: {e}")))?;
+
+    if let Ok(reply) = serde_json::from_str::<ErrorReply>(&response) {
+        return Err(Error::Api(reply.error).into());
+    }
+    Ok(response)
 }
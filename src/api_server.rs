@@ -4,17 +4,26 @@
 //
 
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 
 use std::io::prelude::*;
+use std::os::unix::io::AsRawFd;
 use std::os::unix::net::{UnixListener, UnixStream};
 
 use anyhow::{Context, Result};
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use nix::unistd::geteuid;
 
+use crate::affinity::CpuAffinity;
+use crate::cgroup::{Cgroup, CgroupLimits};
+use crate::cli_instance::DeviceInfo;
+use crate::error::{Error, ErrorReply};
+use crate::memory::guest_memory_regions;
+use crate::numa::memory_per_node;
 use crate::vmm_comm_trait::VMMComm;
-use dragonball::api::v1::{VmmRequest, VmmResponse};
+use dragonball::api::v1::{InstanceInfo, VmmRequest, VmmResponse};
 use dragonball::vcpu::VcpuResizeInfo;
-use serde_json::Value;
+use serde_json::{json, Value};
 
 use vmm_sys_util::eventfd::EventFd;
 
@@ -22,6 +31,12 @@ pub struct ApiServer {
     pub to_vmm: Option<Sender<VmmRequest>>,
     pub from_vmm: Option<Arc<Mutex<Receiver<VmmResponse>>>>,
     pub to_vmm_fd: EventFd,
+    pub vmm_shared_info: Arc<RwLock<InstanceInfo>>,
+    pub devices: Arc<Mutex<Vec<DeviceInfo>>>,
+    pub cgroup: Option<Arc<Cgroup>>,
+    pub affinity: CpuAffinity,
+    /// The memory file of the hugetlbfs memory type, to find the guest memory
+    pub mem_file_path: String,
 }
 
 impl VMMComm for ApiServer {
@@ -42,49 +57,136 @@ impl ApiServer {
         to_vmm: Option<Sender<VmmRequest>>,
         from_vmm: Option<Arc<Mutex<Receiver<VmmResponse>>>>,
         to_vmm_fd: EventFd,
+        vmm_shared_info: Arc<RwLock<InstanceInfo>>,
+        devices: Arc<Mutex<Vec<DeviceInfo>>>,
+        cgroup: Option<Arc<Cgroup>>,
+        affinity: CpuAffinity,
     ) -> Self {
         ApiServer {
             to_vmm,
             from_vmm,
             to_vmm_fd,
+            vmm_shared_info,
+            devices,
+            cgroup,
+            affinity,
+            mem_file_path: String::new(),
         }
     }
 
-    pub fn run_api_server(&mut self, api_sock_path: &str) -> Result<()> {
-        let unix_listener = UnixListener::bind(api_sock_path)?;
+    /// Create the api socket, before the VM is started so that a failure
+    /// stops `create`.
+    pub fn bind(api_sock_path: &str) -> Result<UnixListener> {
+        let unix_listener = UnixListener::bind(api_sock_path)
+            .with_context(|| format!("Failed to create the api socket {api_sock_path:?}"))?;
         println!("dbs-cli: api server created in api_sock_path {api_sock_path:?}. Start waiting for connections from the client side.");
+        Ok(unix_listener)
+    }
 
+    pub fn run_api_server(&mut self, unix_listener: UnixListener) -> Result<()> {
         // put the server logic in a loop to accept several connections
         loop {
-            let (unix_stream, _socket_address) = unix_listener
+            let (mut unix_stream, _socket_address) = unix_listener
                 .accept()
                 .context("Failed at accepting a connection on the unix listener")?;
-            self.handle_stream(unix_stream)?;
+            // a failed request is replied to its client, and does not stop the server
+            if let Err(e) = self.handle_stream(&mut unix_stream) {
+                eprintln!("dbs-cli: api request failed: {e:#}");
+                let _ = Self::reply(&mut unix_stream, serde_json::to_value(ErrorReply::new(&e))?);
+            }
         }
     }
 
-    pub fn handle_stream(&mut self, mut unix_stream: UnixStream) -> Result<()> {
+    pub fn handle_stream(&mut self, unix_stream: &mut UnixStream) -> Result<()> {
         let mut message = String::new();
         unix_stream
             .read_to_string(&mut message)
-            .context("Failed at reading the unix stream")?;
+            .map_err(|e| Error::Transport(format!("Failed at reading the unix stream: {e}")))?;
+        // the request is read anyway, so the client gets the reply
+        Self::check_client(unix_stream)?;
 
         // Parse the string of data into serde_json::Value.
-        let v: Value = serde_json::from_str(&message)?;
+        let v: Value = serde_json::from_str(&message)
+            .map_err(|e| Error::Validation(format!("invalid request {message:?}: {e}")))?;
 
         match v["action"].as_str() {
             Some("resize_vcpu") => {
                 let resize_vcpu_cfg = VcpuResizeInfo {
                     vcpu_count: v["vcpu_count"].as_u64().map(|count| count as u8),
                 };
-                return self.resize_vcpu(resize_vcpu_cfg);
+                self.resize_vcpu(resize_vcpu_cfg)?;
+                // the new vcpu threads are created in the group and with the
+                // CPUs of the VMM thread
+                let tids = self.vmm_shared_info.read().unwrap().tids.clone();
+                if let Some(cgroup) = &self.cgroup {
+                    cgroup.place_vcpus(&tids)?;
+                }
+                self.affinity.pin_vcpus(&tids)
+            }
+            Some("update_cgroup") => {
+                let limits: CgroupLimits = serde_json::from_value(v.clone())
+                    .map_err(|e| Error::Validation(format!("invalid cgroup limits: {e}")))?;
+                match &self.cgroup {
+                    Some(cgroup) => cgroup.update(&limits),
+                    None => Err(Error::Validation(String::from("the VM has no cgroup")).into()),
+                }
+            }
+            Some("get_instance_info") => {
+                let response = self.instance_info();
+                Self::reply(unix_stream, response)
             }
-            _ => {
-                println!("Unknown Actions");
+            Some("get_vm_config") => {
+                let response = serde_json::to_value(self.get_vm_configuration()?)?;
+                Self::reply(unix_stream, response)
             }
+            Some("get_devices") => {
+                let response = serde_json::to_value(&*self.devices.lock().unwrap())?;
+                Self::reply(unix_stream, response)
+            }
+            Some("get_status") => {
+                let response = json!({
+                    "instance_info": self.instance_info(),
+                    "vm_config": self.get_vm_configuration()?,
+                    "devices": &*self.devices.lock().unwrap(),
+                    "memory_per_node": guest_memory_regions(&self.mem_file_path)
+                        .and_then(|regions| memory_per_node(&regions))
+                        .ok(),
+                });
+                Self::reply(unix_stream, response)
+            }
+            _ => Err(Error::Validation(format!("unknown action in {message:?}")).into()),
         }
+    }
 
-        println!("{message}");
+    /// Only root and the user running the VMM may use the api server.
+    fn check_client(unix_stream: &UnixStream) -> Result<()> {
+        let credentials = getsockopt(unix_stream.as_raw_fd(), PeerCredentials).map_err(|e| {
+            Error::Authorization(format!("Failed to get the credentials of the client: {e}"))
+        })?;
+        let uid = geteuid().as_raw();
+        if credentials.uid() != 0 && credentials.uid() != uid {
+            return Err(Error::Authorization(format!(
+                "uid {} is not allowed to use the api server of uid {uid}",
+                credentials.uid()
+            ))
+            .into());
+        }
         Ok(())
     }
+
+    fn instance_info(&self) -> Value {
+        let info = self.vmm_shared_info.read().unwrap();
+        json!({
+            "id": info.id,
+            "state": format!("{:?}", info.state),
+            "vmm_version": info.vmm_version,
+            "pid": info.pid,
+        })
+    }
+
+    fn reply(unix_stream: &mut UnixStream, response: Value) -> Result<()> {
+        unix_stream
+            .write_all(response.to_string().as_bytes())
+            .context("Failed at writing onto the unix stream")
+    }
 }
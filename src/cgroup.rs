@@ -0,0 +1,330 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use nix::fcntl::{openat, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{chown, Gid, Uid};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::parser::DBSArgs;
+
+/// Mount point of the cgroup v2 hierarchy.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Threaded child groups of the VMM threads and of the vcpu threads.
+const VMM_GROUP: &str = "vmm";
+const VCPU_GROUP: &str = "vcpu";
+/// Controllers of the limits, the cpu one is also used by the child groups.
+const CONTROLLERS: [&str; 4] = ["cpu", "memory", "io", "pids"];
+const THREADED_CONTROLLER: &str = "cpu";
+/// The controllers allowed in the subtree of a group with threaded children.
+const THREAD_MODE_CONTROLLERS: [&str; 4] = ["cpu", "cpuset", "perf_event", "pids"];
+/// Files written by the jailed VMM: the vcpu placement, which also needs the
+/// `cgroup.procs` of the common ancestor, and the limits updated by the api.
+const DELEGATED_FILES: [&str; 8] = [
+    "cgroup.procs",
+    "cgroup.threads",
+    "vmm/cgroup.threads",
+    "vcpu/cgroup.threads",
+    "cpu.max",
+    "memory.max",
+    "io.max",
+    "pids.max",
+];
+
+/// Resource limits of the cgroup, written as is to the interface files.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CgroupLimits {
+    /// cpu.max, `<quota> [<period>]` in microseconds or `max`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_max: Option<String>,
+    /// memory.max, in bytes or `max`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_max: Option<String>,
+    /// io.max lines, `<major>:<minor> <key>=<value>...`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub io_max: Vec<String>,
+    /// pids.max, a number or `max`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids_max: Option<String>,
+}
+
+impl CgroupLimits {
+    pub fn from_args(args: &DBSArgs) -> Self {
+        let create_args = &args.create_args;
+        CgroupLimits {
+            cpu_max: create_args.cpu_max.clone(),
+            memory_max: create_args.memory_max.clone(),
+            io_max: create_args.io_max.clone(),
+            pids_max: create_args.pids_max.clone(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == CgroupLimits::default()
+    }
+}
+
+/// The cgroup v2 group of the VM given with `--cgroup`.
+///
+/// The process is put in its `vmm` threaded child group, and the vcpu threads
+/// in the `vcpu` one. The group is used through a directory file descriptor,
+/// so it can still be updated from a chroot.
+pub struct Cgroup {
+    path: PathBuf,
+    dir: File,
+    /// The group is removed with the VM if it was created for it
+    created: bool,
+    /// The group of the process before it was moved
+    original: Option<PathBuf>,
+}
+
+impl Cgroup {
+    /// Create or join the cgroup of `--cgroup`, apply the limits and move the
+    /// process into it. Returns `None` if `--cgroup` is not set.
+    pub fn setup(args: &DBSArgs) -> Result<Option<Self>> {
+        let path = match &args.create_args.cgroup {
+            Some(path) => resolve(path)?,
+            None => return Ok(None),
+        };
+        if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+            return Err(anyhow!("cgroup v2 is not mounted on {CGROUP_ROOT}"));
+        }
+
+        // only the group of the VM is created, its parent is managed by the host
+        let created = !path.exists();
+        if created {
+            std::fs::create_dir(&path)
+                .with_context(|| format!("Failed to create cgroup {path:?}"))?;
+        }
+        let dir = File::open(&path).with_context(|| format!("Failed to open cgroup {path:?}"))?;
+        let cgroup = Cgroup {
+            path,
+            dir,
+            created,
+            original: current_cgroup(),
+        };
+
+        let jail_owner = match (args.create_args.uid, args.create_args.gid) {
+            (None, None) => None,
+            (uid, gid) => Some((uid.map(Uid::from_raw), gid.map(Gid::from_raw))),
+        };
+        let result = cgroup
+            .init(&CgroupLimits::from_args(args))
+            .and_then(|()| match jail_owner {
+                Some((uid, gid)) => cgroup.delegate(uid, gid),
+                None => Ok(()),
+            });
+        if let Err(e) = result {
+            cgroup.remove();
+            return Err(e).with_context(|| format!("Failed to set up cgroup {:?}", cgroup.path));
+        }
+        Ok(Some(cgroup))
+    }
+
+    fn init(&self, limits: &CgroupLimits) -> Result<()> {
+        // the controllers of the limits are enabled by the parent group, among
+        // the ones it has, a limit of a missing controller fails to be written
+        let parent = self.path.parent().unwrap_or_else(|| Path::new(CGROUP_ROOT));
+        let available = std::fs::read_to_string(parent.join("cgroup.controllers"))?;
+        let controllers: Vec<&str> = available
+            .split_whitespace()
+            .filter(|controller| CONTROLLERS.contains(controller))
+            .collect();
+        let subtree_control = std::fs::read_to_string(parent.join("cgroup.subtree_control"))?;
+        let missing: Vec<String> = controllers
+            .iter()
+            .filter(|controller| {
+                !subtree_control
+                    .split_whitespace()
+                    .any(|c| c == **controller)
+            })
+            .map(|controller| format!("+{controller}"))
+            .collect();
+        if !missing.is_empty() {
+            check_no_processes(parent)?;
+            std::fs::write(parent.join("cgroup.subtree_control"), missing.join(" "))
+                .context("Failed to enable the controllers in the parent group")?;
+        }
+        if !self.created {
+            self.check_reusable()?;
+        }
+
+        for group in [VMM_GROUP, VCPU_GROUP] {
+            let path = self.path.join(group);
+            if !path.exists() {
+                std::fs::create_dir(&path)?;
+            }
+            std::fs::write(path.join("cgroup.type"), "threaded")
+                .with_context(|| format!("Failed to make {path:?} threaded"))?;
+        }
+        if controllers.contains(&THREADED_CONTROLLER) {
+            self.write("cgroup.subtree_control", &format!("+{THREADED_CONTROLLER}"))?;
+        }
+
+        self.update(limits)?;
+
+        // a process joins the threaded root, then its threads join the child
+        // groups, the threads created later stay in the group of their creator
+        let pid = std::process::id().to_string();
+        self.write("cgroup.procs", &pid)?;
+        self.write(&format!("{VMM_GROUP}/cgroup.threads"), &pid)
+    }
+
+    /// Make sure the existing group can get the threaded child groups: it must
+    /// be a domain, with no other controller than the threaded ones enabled.
+    fn check_reusable(&self) -> Result<()> {
+        let cgroup_type = std::fs::read_to_string(self.path.join("cgroup.type"))?;
+        let cgroup_type = cgroup_type.trim();
+        if cgroup_type != "domain" && cgroup_type != "domain threaded" {
+            return Err(anyhow!(
+                "the existing cgroup {:?} is {cgroup_type:?}, it should be a domain: use a new group",
+                self.path
+            ));
+        }
+
+        let subtree_control = std::fs::read_to_string(self.path.join("cgroup.subtree_control"))?;
+        let domain: Vec<&str> = subtree_control
+            .split_whitespace()
+            .filter(|controller| !THREAD_MODE_CONTROLLERS.contains(controller))
+            .collect();
+        if !domain.is_empty() {
+            return Err(anyhow!(
+                "the existing cgroup {:?} has controllers enabled for its child groups that threaded groups cannot use ({}): disable them in its cgroup.subtree_control or use a new group",
+                self.path,
+                domain.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    /// Give the files the VMM writes to the uid and gid it is jailed with, so
+    /// it still places the vcpu threads and updates the limits once it has
+    /// dropped its privileges. The directories stay owned by root.
+    fn delegate(&self, uid: Option<Uid>, gid: Option<Gid>) -> Result<()> {
+        for file in DELEGATED_FILES {
+            let path = self.path.join(file);
+            // the limit files of the controllers the parent does not have
+            if !path.exists() {
+                continue;
+            }
+            chown(&path, uid, gid)
+                .with_context(|| format!("Failed to give {path:?} to the jailed VMM"))?;
+        }
+        Ok(())
+    }
+
+    /// Write the limits that are set.
+    pub fn update(&self, limits: &CgroupLimits) -> Result<()> {
+        if let Some(cpu_max) = &limits.cpu_max {
+            self.write("cpu.max", cpu_max)?;
+        }
+        if let Some(memory_max) = &limits.memory_max {
+            self.write("memory.max", memory_max)?;
+        }
+        for io_max in limits.io_max.iter() {
+            self.write("io.max", io_max)?;
+        }
+        if let Some(pids_max) = &limits.pids_max {
+            self.write("pids.max", pids_max)?;
+        }
+        Ok(())
+    }
+
+    /// Move the vcpu threads, `(vcpu id, tid)`, into the `vcpu` group.
+    pub fn place_vcpus(&self, tids: &[(u8, u32)]) -> Result<()> {
+        for (_, tid) in tids.iter() {
+            self.write(&format!("{VCPU_GROUP}/cgroup.threads"), &tid.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<()> {
+        let fd = openat(
+            self.dir.as_raw_fd(),
+            file,
+            OFlag::O_WRONLY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .with_context(|| format!("Failed to open {file} of cgroup {:?}", self.path))?;
+        // SAFETY: the fd has just been opened and is owned by nobody else.
+        let mut file_handle = unsafe { File::from_raw_fd(fd) };
+        file_handle.write_all(value.as_bytes()).with_context(|| {
+            format!(
+                "Failed to write {value:?} to {file} of cgroup {:?}",
+                self.path
+            )
+        })
+    }
+
+    /// Move the process back to its original group and remove the child
+    /// groups, and the group itself if it was created for the VM.
+    pub fn remove(&self) {
+        // a group can only be removed once it holds no process
+        if let Some(original) = &self.original {
+            let _ = std::fs::write(
+                original.join("cgroup.procs"),
+                std::process::id().to_string(),
+            );
+        }
+        let _ = std::fs::remove_dir(self.path.join(VCPU_GROUP));
+        let _ = std::fs::remove_dir(self.path.join(VMM_GROUP));
+        if self.created {
+            let _ = std::fs::remove_dir(&self.path);
+        }
+    }
+}
+
+/// Removes the cgroup when dropped, i.e. when the VM exits.
+pub struct CgroupGuard(pub Arc<Cgroup>);
+
+impl Drop for CgroupGuard {
+    fn drop(&mut self) {
+        self.0.remove();
+    }
+}
+
+/// Resolve `--cgroup`, an absolute path in the cgroup mount or a path relative to it.
+fn resolve(path: &str) -> Result<PathBuf> {
+    let path = Path::new(path);
+    if path.is_absolute() && !path.starts_with(CGROUP_ROOT) {
+        return Err(anyhow!("the cgroup {path:?} is not in {CGROUP_ROOT}"));
+    }
+    let path = Path::new(CGROUP_ROOT).join(path);
+    if path == Path::new(CGROUP_ROOT) {
+        return Err(anyhow!("the root cgroup cannot be used"));
+    }
+    Ok(path)
+}
+
+/// Controllers can only be enabled for the child groups of a group without
+/// processes, except the root one.
+fn check_no_processes(group: &Path) -> Result<()> {
+    if group == Path::new(CGROUP_ROOT) {
+        return Ok(());
+    }
+    let procs = std::fs::read_to_string(group.join("cgroup.procs"))?;
+    let pids: Vec<&str> = procs.split_whitespace().collect();
+    if !pids.is_empty() {
+        return Err(anyhow!(
+            "the parent cgroup {group:?} holds processes ({}), so controllers cannot be enabled for the cgroup of the VM: move them to another group first",
+            pids.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// The cgroup v2 group of the process, from `/proc/self/cgroup`.
+fn current_cgroup() -> Option<PathBuf> {
+    let cgroups = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(|path| Path::new(CGROUP_ROOT).join(path.trim_start_matches('/')))
+}
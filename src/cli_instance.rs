@@ -12,30 +12,56 @@ use std::{
     },
 };
 
+use crate::affinity::CpuAffinity;
+use crate::cgroup::Cgroup;
+use crate::kernel_cmdline::KernelCmdline;
+use crate::memory::{guest_memory_regions, prefault_guest_memory};
+use crate::numa::NumaPolicy;
 use crate::vmm_comm_trait::VMMComm;
 use anyhow::{anyhow, Result};
 use seccompiler::BpfProgram;
+use serde_derive::Serialize;
+use slog::info;
 use vmm_sys_util::eventfd::EventFd;
 
-use dragonball::{
-    api::v1::{
-        BlockDeviceConfigInfo, BootSourceConfig, InstanceInfo, VmmRequest, VmmResponse,
-        VsockDeviceConfigInfo,
-    },
-    vm::{CpuTopology, VmConfigInfo},
+use dragonball::api::v1::{
+    BlockDeviceConfigInfo, FsDeviceConfigInfo, InstanceInfo, VirtioNetDeviceConfigInfo, VmmRequest,
+    VmmResponse, VsockDeviceConfigInfo,
 };
 
 use crate::parser::DBSArgs;
+use crate::vm_spec::VmSpec;
 
 const DRAGONBALL_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+/// A device that has been attached to the VM, as reported by the api server.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum DeviceInfo {
+    Block(BlockDeviceConfigInfo),
+    Vsock(VsockDeviceConfigInfo),
+    Net(VirtioNetDeviceConfigInfo),
+    Fs(FsDeviceConfigInfo),
+}
+
 pub struct CliInstance {
     /// VMM instance info directly accessible from runtime
     pub vmm_shared_info: Arc<RwLock<InstanceInfo>>,
+    /// Devices successfully inserted into the VM
+    pub devices: Arc<Mutex<Vec<DeviceInfo>>>,
     pub to_vmm: Option<Sender<VmmRequest>>,
     pub from_vmm: Option<Arc<Mutex<Receiver<VmmResponse>>>>,
     pub to_vmm_fd: EventFd,
-    pub seccomp: BpfProgram,
+    /// Seccomp filter of the VMM thread
+    pub vmm_seccomp: BpfProgram,
+    /// Seccomp filter of the vcpu threads
+    pub vcpu_seccomp: BpfProgram,
+    /// The cgroup of the VM, which the vcpu threads are moved into
+    pub cgroup: Option<Arc<Cgroup>>,
+    /// The host CPUs the vcpu threads are pinned to
+    pub affinity: CpuAffinity,
+    /// The NUMA policy of the guest memory
+    pub numa_policy: Option<NumaPolicy>,
 }
 
 impl VMMComm for CliInstance {
@@ -63,102 +89,126 @@ impl CliInstance {
 
         CliInstance {
             vmm_shared_info,
+            devices: Arc::new(Mutex::new(vec![])),
             to_vmm: None,
             from_vmm: None,
             to_vmm_fd,
-            seccomp: vec![],
+            vmm_seccomp: vec![],
+            vcpu_seccomp: vec![],
+            cgroup: None,
+            affinity: CpuAffinity::default(),
+            numa_policy: None,
         }
     }
 
-    pub fn run_vmm_server(&self, args: DBSArgs) -> Result<()> {
-        if args.boot_args.kernel_path.is_none() || args.boot_args.rootfs_args.rootfs.is_none() {
-            return Err(anyhow!(
-                "kernel path or rootfs path cannot be None when creating the VM"
-            ));
+    pub fn run_vmm_server(&self, args: DBSArgs, kernel_cmdline: KernelCmdline) -> Result<()> {
+        if matches!(&args.create_args.vsock, Some(vsock) if vsock.cid.is_none()) {
+            return Err(anyhow!("the vsock guest cid is not reserved"));
         }
-        let mut serial_path: Option<String> = None;
-
-        if args.create_args.serial_path != "stdio" {
-            serial_path = Some(args.create_args.serial_path);
+        let spec = VmSpec::build(&args, kernel_cmdline)?;
+        for warning in spec.warnings.iter() {
+            eprintln!("dbs-cli: warning: {warning}");
         }
 
-        // configuration
-        let vm_config = VmConfigInfo {
-            vcpu_count: args.create_args.vcpu,
-            max_vcpu_count: args.create_args.max_vcpu,
-            cpu_pm: args.create_args.cpu_pm.clone(),
-            cpu_topology: CpuTopology {
-                threads_per_core: args.create_args.cpu_topology.threads_per_core,
-                cores_per_die: args.create_args.cpu_topology.cores_per_die,
-                dies_per_socket: args.create_args.cpu_topology.dies_per_socket,
-                sockets: args.create_args.cpu_topology.sockets,
-            },
-            vpmu_feature: 0,
-            mem_type: args.create_args.mem_type.clone(),
-            mem_file_path: args.create_args.mem_file_path.clone(),
-            mem_size_mib: args.create_args.mem_size,
-            // as in crate `dragonball` serial_path will be assigned with a default value,
-            // we need a special token to enable the stdio console.
-            serial_path: serial_path.clone(),
-        };
-
-        if let Some(com1_sock_path) = serial_path {
+        if let Some(com1_sock_path) = &spec.vm_config.serial_path {
             // check the existence of the serial path (rm it if exist)
-            // unwrap is safe  because we have check it is Some above.
             let serial_file = Path::new(com1_sock_path.as_str());
             if serial_file.exists() {
                 std::fs::remove_file(serial_file)?;
             }
         }
 
-        // boot source
-        let boot_source_config = BootSourceConfig {
-            // unwrap is safe because we have checked kernel_path in the beginning of run_vmm_server
-            kernel_path: args.boot_args.kernel_path.unwrap(),
-            initrd_path: args.boot_args.initrd_path.clone(),
-            boot_args: Some(args.boot_args.boot_args.clone()),
-        };
-
-        // rootfs
-        let mut block_device_config_info = BlockDeviceConfigInfo::default();
-        block_device_config_info = BlockDeviceConfigInfo {
-            drive_id: String::from("rootfs"),
-            // unwrap is safe because we have checked rootfs path in the beginning of run_vmm_server
-            path_on_host: PathBuf::from(&args.boot_args.rootfs_args.rootfs.unwrap()),
-            is_root_device: args.boot_args.rootfs_args.is_root,
-            is_read_only: args.boot_args.rootfs_args.is_read_only,
-            ..block_device_config_info
-        };
-
         // set vm configuration
-        self.set_vm_configuration(vm_config)
-            .expect("failed to set vm configuration");
+        self.set_vm_configuration(spec.vm_config)?;
 
         // set boot source config
-        self.put_boot_source(boot_source_config)
-            .expect("failed to set boot source");
-
-        // set rootfs
-        self.insert_block_device(block_device_config_info)
-            .expect("failed to set block device");
-
-        if !args.create_args.vsock.is_empty() {
-            // VSOCK config
-            let mut vsock_config_info = VsockDeviceConfigInfo::default();
-            vsock_config_info = VsockDeviceConfigInfo {
-                guest_cid: 42, // dummy value
-                uds_path: Some(args.create_args.vsock),
-                ..vsock_config_info
-            };
+        self.put_boot_source(spec.boot_source)?;
+
+        // set rootfs and extra drives
+        for block_device_config_info in spec.block_devices {
+            self.insert_block_device(block_device_config_info.clone())?;
+            self.devices
+                .lock()
+                .unwrap()
+                .push(DeviceInfo::Block(block_device_config_info));
+        }
 
+        if let Some(vsock_config_info) = spec.vsock {
             // set vsock
-            self.insert_vsock(vsock_config_info)
-                .expect("failed to set vsock socket path");
+            self.insert_vsock(vsock_config_info.clone())?;
+            self.devices
+                .lock()
+                .unwrap()
+                .push(DeviceInfo::Vsock(vsock_config_info));
+        }
+
+        for net_config_info in spec.net {
+            // set virtio-net
+            self.insert_network_device(net_config_info.clone())?;
+            self.devices
+                .lock()
+                .unwrap()
+                .push(DeviceInfo::Net(net_config_info));
+        }
+
+        for fs_config_info in spec.fs {
+            // set virtio-fs
+            self.insert_fs_device(fs_config_info.clone())?;
+            self.devices
+                .lock()
+                .unwrap()
+                .push(DeviceInfo::Fs(fs_config_info));
         }
 
         // start micro-vm
-        self.instance_start().expect("failed to start micro-vm");
+        self.instance_start()?;
+
+        // the guest memory only exists once the VM is started, and is placed
+        // before it is prefaulted
+        if self.numa_policy.is_some() || args.create_args.mem_prefault {
+            let regions = guest_memory_regions(&args.create_args.mem_file_path)?;
+            if let Some(numa_policy) = &self.numa_policy {
+                numa_policy.apply(&regions)?;
+            }
+            if args.create_args.mem_prefault {
+                let populated = prefault_guest_memory(&regions)?;
+                info!(slog_scope::logger(), "prefaulted the guest memory";
+                    "size_mib" => populated >> 20);
+            }
+        }
+
+        let tids = self.vmm_shared_info.read().unwrap().tids.clone();
+        if let Some(cgroup) = &self.cgroup {
+            cgroup.place_vcpus(&tids)?;
+        }
+        self.affinity.pin_vcpus(&tids)?;
 
         Ok(())
     }
+
+    /// Collect the rootfs and the extra drives.
+    pub fn block_devices(args: &DBSArgs) -> Vec<BlockDeviceConfigInfo> {
+        let mut block_device_config_infos = vec![];
+        if let Some(rootfs) = &args.boot_args.rootfs_args.rootfs {
+            block_device_config_infos.push(BlockDeviceConfigInfo {
+                drive_id: String::from("rootfs"),
+                path_on_host: PathBuf::from(rootfs),
+                is_root_device: args.boot_args.rootfs_args.is_root,
+                is_read_only: args.boot_args.rootfs_args.is_read_only,
+                ..BlockDeviceConfigInfo::default()
+            });
+        }
+
+        for drive in args.create_args.drives.iter() {
+            block_device_config_infos.push(BlockDeviceConfigInfo {
+                drive_id: drive.id.clone(),
+                path_on_host: PathBuf::from(&drive.path),
+                is_root_device: drive.root,
+                is_read_only: drive.ro,
+                ..BlockDeviceConfigInfo::default()
+            });
+        }
+
+        block_device_config_infos
+    }
 }
@@ -0,0 +1,124 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use nix::fcntl::OFlag;
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{dup2, fork, pipe2, setsid, ForkResult};
+
+use crate::exit_code;
+use crate::file_lock::{lock_holder, FileLock};
+
+/// Message sent by the daemon to the parent waiting for it
+const READY: &str = "ready";
+
+/// The daemonized dbs-cli, which tells its parent when the VM has started.
+pub struct Daemon {
+    /// Write end of the pipe read by the parent
+    pipe: File,
+    /// `/dev/null`, opened before the VMM may be jailed
+    null: File,
+    /// The pid of the daemon, not of the jailed VMM it may fork
+    pid: u32,
+}
+
+/// Fork dbs-cli into a daemon in a new session.
+///
+/// The parent waits until the daemon reports that the VM has started, then
+/// exits with 0, or until the daemon exits, then exits with its exit code.
+/// The daemon keeps the terminal as stdio until the VM has started, so it
+/// prints its startup errors itself.
+///
+/// This must run before any thread is spawned.
+pub fn daemonize() -> Result<Daemon> {
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .context("Failed to open /dev/null")?;
+    let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC).context("Failed to create the pipe")?;
+    // SAFETY: the fds have just been created and are owned by nobody else.
+    let (read_end, write_end) =
+        unsafe { (File::from_raw_fd(read_fd), File::from_raw_fd(write_fd)) };
+
+    // SAFETY: no thread is spawned yet, so the child can safely run anything.
+    match unsafe { fork() }.context("Failed to fork the daemon")? {
+        ForkResult::Parent { child } => {
+            drop(write_end);
+            let mut message = String::new();
+            let _ = BufReader::new(read_end).read_line(&mut message);
+            let exit_code = match message.trim_end().split_once(' ') {
+                Some((READY, pid)) => {
+                    println!("dbs-cli: the VM is running in the background, pid {pid}");
+                    0
+                }
+                // the daemon exited before the VM started, and printed why
+                _ => match waitpid(child, None) {
+                    Ok(WaitStatus::Exited(_, code)) if code != 0 => code,
+                    Ok(WaitStatus::Signaled(_, signal, _)) => {
+                        exit_code::KILLED_BY_SIGNAL + signal as i32
+                    }
+                    _ => exit_code::STARTUP_ERROR,
+                },
+            };
+            std::process::exit(exit_code);
+        }
+        ForkResult::Child => {
+            drop(read_end);
+            setsid().context("Failed to create the session of the daemon")?;
+            Ok(Daemon {
+                pipe: write_end,
+                null,
+                pid: std::process::id(),
+            })
+        }
+    }
+}
+
+impl Daemon {
+    /// Tell the parent that the VM has started, and detach from the terminal.
+    pub fn ready(mut self) -> Result<()> {
+        writeln!(self.pipe, "{READY} {}", self.pid)
+            .context("Failed to notify the parent of the daemon")?;
+        self.detach_stdio()
+    }
+
+    /// Redirect stdio to `/dev/null`, so the terminal can be closed.
+    pub fn detach_stdio(&self) -> Result<()> {
+        for fd in 0..=2 {
+            dup2(self.null.as_raw_fd(), fd).context("Failed to redirect stdio to /dev/null")?;
+        }
+        Ok(())
+    }
+}
+
+/// The pidfile of `--pidfile`, locked while dbs-cli runs and removed on exit.
+pub struct PidFile {
+    _lock: FileLock,
+}
+
+impl PidFile {
+    pub fn create(path: &str) -> Result<Self> {
+        // a pidfile still locked belongs to a running dbs-cli
+        let lock = FileLock::create(Path::new(path))
+            .with_context(|| format!("Failed to open the pidfile {path:?}"))?
+            .ok_or_else(|| {
+                let holder = lock_holder(Path::new(path))
+                    .map(|holder| holder.pid.to_string())
+                    .unwrap_or_else(|| String::from("unknown"));
+                anyhow!("the pidfile {path:?} is used by the running dbs-cli with pid {holder}")
+            })?;
+
+        let mut file = lock.file();
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())
+            .with_context(|| format!("Failed to write the pidfile {path:?}"))?;
+
+        Ok(PidFile { _lock: lock })
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::instance_dir::InstanceDir;
+use crate::parser::args::DriveArgs;
+use crate::parser::DBSArgs;
+
+/// The id of the drive added by `--scratch-size`.
+pub const SCRATCH_DRIVE_ID: &str = "scratch";
+/// Chunks of zeroes this large are left as holes by the sparse copy.
+const SPARSE_CHUNK_SIZE: usize = 64 << 10;
+const MIB: u64 = 1 << 20;
+
+// FICLONE = _IOW(0x94, 9, int)
+nix::ioctl_write_int!(ficlone, 0x94, 9);
+
+/// Give the VM private writable storage, so the rootfs image is never written:
+/// a clone of rootfs with `--ephemeral` and an empty drive with `--scratch-size`.
+///
+/// `args` is updated to use them. They are created in a per-instance directory
+/// in `--ephemeral-dir` or next to rootfs, removed when the returned guard is dropped.
+pub fn prepare_ephemeral(args: &mut DBSArgs) -> Result<Option<InstanceDir>> {
+    let rootfs_args = &mut args.boot_args.rootfs_args;
+    if !rootfs_args.ephemeral && rootfs_args.scratch_size == 0 {
+        return Ok(None);
+    }
+
+    let rootfs = PathBuf::from(rootfs_args.rootfs.as_ref().ok_or_else(|| {
+        anyhow!("rootfs should be set for an ephemeral rootfs or a scratch drive")
+    })?);
+    let parent = match &rootfs_args.ephemeral_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => rootfs
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."))
+            .to_path_buf(),
+    };
+    let dir = InstanceDir::new_in(&parent)?;
+
+    if rootfs_args.ephemeral {
+        let clone = dir
+            .path()
+            .join(rootfs.file_name().unwrap_or_else(|| "rootfs".as_ref()));
+        clone_image(&rootfs, &clone)
+            .with_context(|| format!("Failed to clone rootfs {rootfs:?} to {clone:?}"))?;
+        rootfs_args.rootfs = Some(clone.to_string_lossy().into_owned());
+    }
+
+    if rootfs_args.scratch_size > 0 {
+        let scratch = dir.path().join("scratch.img");
+        File::create(&scratch)
+            .and_then(|file| file.set_len(rootfs_args.scratch_size * MIB))
+            .with_context(|| format!("Failed to create scratch drive {scratch:?}"))?;
+        args.create_args.drives.push(DriveArgs {
+            id: SCRATCH_DRIVE_ID.to_string(),
+            path: scratch.to_string_lossy().into_owned(),
+            ro: false,
+            root: false,
+        });
+    }
+
+    Ok(Some(dir))
+}
+
+/// Copy `src` to `dst`, sharing the blocks with a reflink when the filesystem
+/// supports it, or with a sparse copy otherwise.
+fn clone_image(src: &Path, dst: &Path) -> Result<()> {
+    let mut input = File::open(src)?;
+    let mut output = OpenOptions::new().write(true).create_new(true).open(dst)?;
+
+    // SAFETY: both file descriptors are valid for the duration of the call.
+    if unsafe { ficlone(output.as_raw_fd(), input.as_raw_fd() as _) }.is_ok() {
+        return Ok(());
+    }
+
+    let len = input.metadata()?.len();
+    let mut chunk = vec![0u8; SPARSE_CHUNK_SIZE];
+    loop {
+        let read = input.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+        if chunk[..read].iter().all(|byte| *byte == 0) {
+            output.seek(SeekFrom::Current(read as i64))?;
+        } else {
+            output.write_all(&chunk[..read])?;
+        }
+    }
+    // a trailing hole is not allocated by the seek alone
+    output.set_len(len)?;
+    output.sync_all()?;
+
+    Ok(())
+}
@@ -0,0 +1,108 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use dragonball::api::v1::VmmActionError;
+use serde_derive::{Deserialize, Serialize};
+
+/// The errors that callers, and the clients of the api server, tell apart by
+/// kind. The other errors stay `anyhow` errors, which may wrap these.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The command line or the config file could not be loaded
+    #[error("{0}")]
+    Config(String),
+    /// The VM specification, or a request to the api server, is invalid
+    #[error("{0}")]
+    Validation(String),
+    /// The VMM refused an action
+    #[error("vmm action error: {0:?}")]
+    VmmAction(#[from] VmmActionError),
+    /// The VMM, or the api server, could not be reached
+    #[error("{0}")]
+    Transport(String),
+    /// The VMM was still not ready after all the retries
+    #[error("{0}")]
+    Timeout(String),
+    /// The client of the api server is not allowed to use it
+    #[error("{0}")]
+    Authorization(String),
+    /// An error replied by the api server to its client
+    #[error("{}", .0.message)]
+    Api(ApiError),
+}
+
+/// The stable error codes of the api server replies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    Config,
+    Validation,
+    VmmAction,
+    Transport,
+    Timeout,
+    Authorization,
+    /// Any other failure of the api server
+    Internal,
+}
+
+/// The error of a failed api server reply.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Whether sending the same request again may succeed
+    pub retryable: bool,
+}
+
+/// A failed api server reply: `{"error": {"code": ..., "message": ..., "retryable": ...}}`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorReply {
+    pub error: ApiError,
+}
+
+impl Error {
+    /// The first `Error` in the chain of an `anyhow` error.
+    pub fn find(e: &anyhow::Error) -> Option<&Error> {
+        e.chain().find_map(|cause| cause.downcast_ref::<Error>())
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::Config(_) => ErrorCode::Config,
+            Error::Validation(_) => ErrorCode::Validation,
+            Error::VmmAction(_) => ErrorCode::VmmAction,
+            Error::Transport(_) => ErrorCode::Transport,
+            Error::Timeout(_) => ErrorCode::Timeout,
+            Error::Authorization(_) => ErrorCode::Authorization,
+            Error::Api(api_error) => api_error.code,
+        }
+    }
+
+    /// Whether the same command or request may succeed when tried again,
+    /// i.e. once the guest is booted and its upcall server is ready. A
+    /// transport error is not: a missing socket or a VMM that stopped stays so.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::VmmAction(VmmActionError::UpcallServerNotReady) | Error::Timeout(_) => true,
+            Error::Api(api_error) => api_error.retryable,
+            _ => false,
+        }
+    }
+}
+
+impl ErrorReply {
+    /// The reply to a request that failed with `e`.
+    pub fn new(e: &anyhow::Error) -> Self {
+        let (code, retryable) = match Error::find(e) {
+            Some(error) => (error.code(), error.is_retryable()),
+            None => (ErrorCode::Internal, false),
+        };
+        ErrorReply {
+            error: ApiError {
+                code,
+                message: format!("{e:#}"),
+                retryable,
+            },
+        }
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+// Exit codes of dbs-cli, mostly of `dbs-cli create`.
+//
+// dragonball stops its event loop with the same status whether the guest
+// shut down, rebooted, or rebooted after a panic or a triple fault, so these
+// cases share `GUEST_STOPPED` and cannot be told apart by the exit code.
+
+use crate::error::{Error, ErrorCode};
+
+/// The guest stopped the VM: shutdown, reboot, or reboot after a panic
+pub const GUEST_STOPPED: i32 = 0;
+/// The VMM stopped on an error while the VM was running. The other commands
+/// exit with it when their request fails.
+pub const VMM_ERROR: i32 = 1;
+/// The VM could not be created: invalid arguments or config, or a startup
+/// failure. The other commands exit with it on invalid arguments.
+pub const STARTUP_ERROR: i32 = 2;
+/// A retryable error: the upcall server of the VMM was not ready yet
+pub const TRANSIENT_ERROR: i32 = 3;
+/// The api server refused the client
+pub const NOT_AUTHORIZED: i32 = 4;
+/// The VMM stopped on an error it did not expect, i.e. a bug of dragonball
+pub const VMM_UNEXPECTED_ERROR: i32 = 5;
+/// dbs-cli, or the jailed VMM, was stopped by a signal, whose number is added
+/// to this
+pub const KILLED_BY_SIGNAL: i32 = 128;
+
+/// The statuses of the dragonball event loop, see `dragonball::vmm`.
+const VMM_EXIT_OK: i32 = 0;
+const VMM_EXIT_GENERIC_ERROR: i32 = 1;
+const VMM_EXIT_UNEXPECTED_ERROR: i32 = 2;
+
+/// The exit code of dbs-cli for the status of the VMM event loop. The
+/// statuses that dragonball does not document are VMM errors.
+pub fn from_vmm(vmm_exit_code: i32) -> i32 {
+    match vmm_exit_code {
+        VMM_EXIT_OK => GUEST_STOPPED,
+        VMM_EXIT_GENERIC_ERROR => VMM_ERROR,
+        VMM_EXIT_UNEXPECTED_ERROR => VMM_UNEXPECTED_ERROR,
+        _ => VMM_ERROR,
+    }
+}
+
+/// The exit code of dbs-cli for an error, `fatal` unless the error is
+/// retryable, an invalid argument or config, or a refused client.
+pub fn from_error(e: &anyhow::Error, fatal: i32) -> i32 {
+    match Error::find(e) {
+        Some(error) if error.is_retryable() => TRANSIENT_ERROR,
+        Some(error) => match error.code() {
+            ErrorCode::Config | ErrorCode::Validation => STARTUP_ERROR,
+            ErrorCode::Authorization => NOT_AUTHORIZED,
+            _ => fatal,
+        },
+        None => fatal,
+    }
+}
+
+/// A description of an exit code, for the log.
+pub fn reason(exit_code: i32) -> String {
+    match exit_code {
+        GUEST_STOPPED => String::from("the guest stopped the VM"),
+        VMM_ERROR => String::from("the VMM stopped on an error"),
+        STARTUP_ERROR => String::from("the VM could not be created"),
+        TRANSIENT_ERROR => String::from("a retryable error stopped dbs-cli"),
+        NOT_AUTHORIZED => String::from("the client is not authorized"),
+        VMM_UNEXPECTED_ERROR => String::from("the VMM stopped on an unexpected error"),
+        code if code > KILLED_BY_SIGNAL => {
+            format!("stopped by signal {}", code - KILLED_BY_SIGNAL)
+        }
+        code => format!("unknown exit code {code}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use dragonball::api::v1::VmmActionError;
+
+    use super::*;
+
+    #[test]
+    fn test_from_vmm() {
+        assert_eq!(from_vmm(0), GUEST_STOPPED);
+        assert_eq!(from_vmm(1), VMM_ERROR);
+        assert_eq!(from_vmm(2), VMM_UNEXPECTED_ERROR);
+        assert_eq!(from_vmm(42), VMM_ERROR);
+    }
+
+    #[test]
+    fn test_from_error() {
+        let error = |e: Error| anyhow::Error::from(e).context("Failed to create the VM");
+
+        assert_eq!(
+            from_error(&error(Error::Config(String::new())), VMM_ERROR),
+            STARTUP_ERROR
+        );
+        assert_eq!(
+            from_error(&error(Error::Validation(String::new())), VMM_ERROR),
+            STARTUP_ERROR
+        );
+        assert_eq!(
+            from_error(&error(Error::Authorization(String::new())), VMM_ERROR),
+            NOT_AUTHORIZED
+        );
+        assert_eq!(
+            from_error(&error(Error::Timeout(String::new())), VMM_ERROR),
+            TRANSIENT_ERROR
+        );
+        assert_eq!(
+            from_error(
+                &error(Error::VmmAction(VmmActionError::UpcallServerNotReady)),
+                VMM_ERROR
+            ),
+            TRANSIENT_ERROR
+        );
+        assert_eq!(
+            from_error(
+                &error(Error::VmmAction(VmmActionError::InvalidVMID)),
+                STARTUP_ERROR
+            ),
+            STARTUP_ERROR
+        );
+        assert_eq!(
+            from_error(&error(Error::Transport(String::new())), VMM_ERROR),
+            VMM_ERROR
+        );
+        assert_eq!(from_error(&anyhow!("failed"), VMM_ERROR), VMM_ERROR);
+    }
+
+    #[test]
+    fn test_reason() {
+        assert_eq!(reason(KILLED_BY_SIGNAL + 15), "stopped by signal 15");
+        assert_eq!(
+            reason(VMM_UNEXPECTED_ERROR),
+            "the VMM stopped on an unexpected error"
+        );
+    }
+}
@@ -0,0 +1,149 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::stat::{major, minor};
+
+/// An advisory `flock`, released when dropped or when the process exits,
+/// however it exits.
+///
+/// The drive images, the vsock CID registry and the pidfile are locked with
+/// it, and `lock_holder` tells who holds a lock taken by another process.
+pub struct FileLock {
+    file: File,
+    /// The lock file to remove when dropped, with the pid of its creator
+    created: Option<(PathBuf, u32)>,
+}
+
+impl FileLock {
+    /// Lock the existing file at `path`, shared or exclusive. Returns `None`
+    /// if another process holds a conflicting lock.
+    pub fn lock(path: &Path, exclusive: bool) -> Result<Option<Self>> {
+        let file = File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+        if !try_flock(&file, exclusive).with_context(|| format!("Failed to lock {path:?}"))? {
+            return Ok(None);
+        }
+        Ok(Some(FileLock {
+            file,
+            created: None,
+        }))
+    }
+
+    /// Create and lock the lock file at `path`, exclusive. Returns `None` if
+    /// another process holds it.
+    ///
+    /// The lock file is removed when the lock is dropped by the process that
+    /// created it, not by its forked children, e.g. the jailed VMM.
+    pub fn create(path: &Path) -> Result<Option<Self>> {
+        loop {
+            let file = match open_lock_file(path) {
+                Ok(file) => file,
+                // removed by its previous holder since it was found
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("Failed to open {path:?}")),
+            };
+            if !try_flock(&file, true).with_context(|| format!("Failed to lock {path:?}"))? {
+                return Ok(None);
+            }
+
+            // the previous holder removes the file before unlocking it, so a
+            // file opened before that is no longer the lock file
+            let locked = file.metadata()?;
+            match std::fs::metadata(path) {
+                Ok(current) if current.dev() == locked.dev() && current.ino() == locked.ino() => {
+                    return Ok(Some(FileLock {
+                        file,
+                        created: Some((path.to_path_buf(), std::process::id())),
+                    }));
+                }
+                _ => continue,
+            }
+        }
+    }
+
+    /// The locked file, only opened for reading if it is a lock file left by
+    /// another user.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // removed while still locked, the file is closed afterwards
+        if let Some((path, pid)) = &self.created {
+            if std::process::id() == *pid {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Create the lock file, or open the existing one. An existing file is
+/// opened without `O_CREAT`, which `protected_regular` refuses for the files
+/// of other users in a shared sticky directory.
+fn open_lock_file(path: &Path) -> std::io::Result<File> {
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        result => return result,
+    }
+    match OpenOptions::new().write(true).open(path) {
+        // a lock file left by another user can still be locked
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => File::open(path),
+        result => result,
+    }
+}
+
+/// Take the lock without blocking, returns `false` if another process holds
+/// a conflicting lock.
+fn try_flock(file: &File, exclusive: bool) -> nix::Result<bool> {
+    let lock = if exclusive {
+        FlockArg::LockExclusiveNonblock
+    } else {
+        FlockArg::LockSharedNonblock
+    };
+    match flock(file.as_raw_fd(), lock) {
+        Ok(()) => Ok(true),
+        Err(Errno::EWOULDBLOCK) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// A process holding a `flock` on a file.
+pub struct LockHolder {
+    pub pid: u32,
+    pub exclusive: bool,
+}
+
+/// Find a process holding a `flock` on `path`, from `/proc/locks`.
+pub fn lock_holder(path: &Path) -> Option<LockHolder> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let locks = std::fs::read_to_string("/proc/locks").ok()?;
+
+    // e.g. "1: FLOCK  ADVISORY  WRITE 1234 fd:01:393218 0 EOF",
+    // the lines of blocked waiters have a "->" after the id
+    let id = format!(
+        "{:02x}:{:02x}:{}",
+        major(metadata.dev()),
+        minor(metadata.dev()),
+        metadata.ino()
+    );
+    locks
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+        .find(|fields| fields.len() >= 6 && fields[1] == "FLOCK" && fields[5] == id)
+        .and_then(|fields| {
+            Some(LockHolder {
+                pid: fields[4].parse().ok()?,
+                exclusive: fields[3] == "WRITE",
+            })
+        })
+}
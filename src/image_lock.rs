@@ -0,0 +1,56 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::cli_instance::CliInstance;
+use crate::file_lock::{lock_holder, FileLock};
+use crate::parser::DBSArgs;
+
+/// The lock on a drive image, released when dropped or when the process exits.
+pub struct DriveLock {
+    _lock: FileLock,
+}
+
+/// Lock the image of every drive, so that no other VM writes a drive of this
+/// one, and no other VM reads a drive this one writes.
+///
+/// The locks are advisory `flock`s: shared for the read-only drives, and
+/// exclusive for the read-write ones. An ephemeral rootfs is only read.
+pub fn lock_drives(args: &DBSArgs) -> Result<Vec<DriveLock>> {
+    let rootfs_args = &args.boot_args.rootfs_args;
+    let mut locks = vec![];
+
+    for info in CliInstance::block_devices(args) {
+        let read_only = info.is_read_only || (rootfs_args.ephemeral && info.drive_id == "rootfs");
+        let path = Path::new(&info.path_on_host);
+        match FileLock::lock(path, !read_only)
+            .with_context(|| format!("Failed to lock drive {:?}", info.drive_id))?
+        {
+            Some(lock) => locks.push(DriveLock { _lock: lock }),
+            None => {
+                let access = if read_only { "read" } else { "written" };
+                let holder = match lock_holder(path) {
+                    Some(holder) => format!(
+                        "used {} by the process with pid {}",
+                        if holder.exclusive {
+                            "read-write"
+                        } else {
+                            "read-only"
+                        },
+                        holder.pid
+                    ),
+                    None => String::from("locked by another instance"),
+                };
+                return Err(anyhow!(
+                    "drive {:?} {path:?} cannot be {access}, it is {holder}",
+                    info.drive_id
+                ));
+            }
+        }
+    }
+
+    Ok(locks)
+}
@@ -0,0 +1,62 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::ffi::{CString, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// A private scratch directory for the files generated for one VM.
+///
+/// It is removed with everything in it when dropped, i.e. when dbs-cli exits.
+pub struct InstanceDir {
+    path: PathBuf,
+}
+
+impl InstanceDir {
+    pub fn new() -> Result<Self> {
+        Self::create(&std::env::temp_dir().join(format!("dbs-cli-{}-", std::process::id())))
+    }
+
+    /// Create the directory in `parent` instead of the temporary directory,
+    /// for files that must live on the filesystem of `parent`.
+    pub fn new_in<P: AsRef<Path>>(parent: P) -> Result<Self> {
+        Self::create(
+            &parent
+                .as_ref()
+                .join(format!(".dbs-cli-{}-", std::process::id())),
+        )
+    }
+
+    /// Create a new directory named `prefix` followed by random characters,
+    /// only accessible to the user of dbs-cli, so that nobody else can put
+    /// files in it or have it removed.
+    fn create(prefix: &Path) -> Result<Self> {
+        let mut template = prefix.as_os_str().as_bytes().to_vec();
+        template.extend_from_slice(b"XXXXXX");
+        let mut template = CString::new(template)?.into_bytes_with_nul();
+        // SAFETY: the template is a nul-terminated buffer, whose X are replaced
+        // in place.
+        let ret = unsafe { libc::mkdtemp(template.as_mut_ptr() as *mut libc::c_char) };
+        if ret.is_null() {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to create the instance directory in {prefix:?}"));
+        }
+        template.pop();
+
+        Ok(InstanceDir {
+            path: PathBuf::from(OsString::from_vec(template)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for InstanceDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
@@ -0,0 +1,355 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashSet;
+use std::fs::Permissions;
+use std::io::ErrorKind;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use nix::fcntl::OFlag;
+use nix::mount::{mount, MsFlags};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::wait::WaitStatus;
+use nix::unistd::{
+    chdir, chown, chroot, close, fork, getgid, pipe2, setgid, setgroups, setuid, ForkResult, Gid,
+    Uid,
+};
+
+use crate::daemon::Daemon;
+use crate::exit_code;
+use crate::parser::DBSArgs;
+use crate::signal::{block_child_signal, unblock_child_signal, wait_child};
+
+/// Where procfs is mounted in the chroot.
+const JAIL_PROC: &str = "proc";
+/// Sticky bit and group access added to the chroot directory
+const SHARED_ROOT_MODE: u32 = 0o1070;
+
+/// Close the file descriptors inherited from the parent process, except stdio.
+///
+/// This must run before any file is opened, and before any thread is spawned.
+pub fn close_inherited_fds() -> Result<()> {
+    let fds: Vec<i32> = std::fs::read_dir("/proc/self/fd")
+        .context("Failed to list the open file descriptors")?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .filter(|fd| *fd > 2)
+        .collect();
+
+    // the fd of the directory listing is already closed and fails with EBADF
+    for fd in fds {
+        let _ = nix::unistd::close(fd);
+    }
+    Ok(())
+}
+
+/// Confine the VMM in `--chroot` when it is set.
+///
+/// The kernel, initrd and drives are linked into the chroot and `args` is
+/// rewritten to use them. Then the process forks a child in a new pid
+/// namespace, which moves to new mount, ipc and net namespaces, chroots and
+/// drops to `--uid` and `--gid`, then returns `None` to go on creating the VM.
+/// The parent waits for it, removes the files it linked, and returns
+/// `Some(exit code)` of the child.
+///
+/// Unix sockets are put in the chroot under their file name, so they are at
+/// `<chroot>/<file name>` on the host. The files dbs-cli generated in the
+/// `generated` directories, e.g. the ephemeral rootfs clone, are given to
+/// `--uid` and `--gid` so the VMM can write them.
+///
+/// A daemon parent detaches from the terminal while it waits, the jailed VMM
+/// reports to the process waiting for the daemon.
+pub fn enter_jail(
+    args: &mut DBSArgs,
+    daemon: Option<&Daemon>,
+    generated: &[&Path],
+) -> Result<Option<i32>> {
+    let root = match &args.create_args.chroot {
+        Some(root) => std::fs::canonicalize(root)
+            .with_context(|| format!("Invalid chroot directory {root:?}"))?,
+        None => return Ok(None),
+    };
+    let uid = args.create_args.uid.map(Uid::from_raw);
+    let gid = args.create_args.gid.map(Gid::from_raw);
+
+    let links = link_files(args, &root, generated, uid, gid)?;
+    let root_owner = match gid.map(|gid| share_root(&root, gid)).transpose() {
+        Ok(root_owner) => root_owner,
+        Err(e) => {
+            remove_links(&links);
+            return Err(e);
+        }
+    };
+    // closed by the parent when it exits, see PR_SET_PDEATHSIG below
+    let (parent_alive, parent_alive_writer) =
+        pipe2(OFlag::O_CLOEXEC).context("Failed to create the pipe of the jail")?;
+
+    // only the children of the process move to the new pid namespace
+    unshare(CloneFlags::CLONE_NEWPID).context("Failed to create the pid namespace of the jail")?;
+
+    block_child_signal()?;
+
+    // SAFETY: no thread is spawned yet, so the child can safely run anything.
+    match unsafe { fork() }.context("Failed to fork the jailed VMM")? {
+        ForkResult::Parent { child } => {
+            let _ = close(parent_alive);
+            if let Some(daemon) = daemon {
+                daemon.detach_stdio()?;
+            }
+            // the jailed VMM stops the VM and exits on the stop signals
+            let exit_code = match wait_child(child) {
+                Ok(WaitStatus::Exited(_, code)) => code,
+                Ok(WaitStatus::Signaled(_, signal, _)) => {
+                    exit_code::KILLED_BY_SIGNAL + signal as i32
+                }
+                _ => exit_code::VMM_ERROR,
+            };
+            remove_links(&links);
+            let _ = std::fs::remove_dir(root.join(JAIL_PROC));
+            if let Some(root_owner) = root_owner {
+                root_owner.restore(&root);
+            }
+            let _ = close(parent_alive_writer);
+            Ok(Some(exit_code))
+        }
+        ForkResult::Child => {
+            unblock_child_signal()?;
+            // do not outlive the parent, which cleans the chroot up
+            // SAFETY: PR_SET_PDEATHSIG takes a signal number and has no side effect on memory.
+            unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+            // the parent may have exited before, the pipe is then closed on
+            // the other end: getppid() cannot tell, it is 0 in the pid namespace
+            let _ = close(parent_alive_writer);
+            let mut fds = [PollFd::new(parent_alive, PollFlags::POLLIN)];
+            if poll(&mut fds, 0).context("Failed to check the parent of the jail")? > 0 {
+                return Err(anyhow!("the parent of the jailed VMM has exited"));
+            }
+            let _ = close(parent_alive);
+
+            // the mounts of the jail go away with it
+            unshare(CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWIPC | CloneFlags::CLONE_NEWNET)
+                .context("Failed to create the namespaces of the jail")?;
+            // keep the mounts of the jail out of the host
+            mount(
+                None::<&str>,
+                "/",
+                None::<&str>,
+                MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+                None::<&str>,
+            )
+            .context("Failed to make the mounts of the jail private")?;
+            let proc_dir = root.join(JAIL_PROC);
+            std::fs::create_dir_all(&proc_dir)?;
+            mount(
+                Some("proc"),
+                &proc_dir,
+                Some("proc"),
+                MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+                None::<&str>,
+            )
+            .context("Failed to mount /proc in the jail")?;
+
+            chroot(&root).context("Failed to chroot")?;
+            chdir("/")?;
+
+            if uid.is_some() || gid.is_some() {
+                setgroups(&[gid.unwrap_or_else(getgid)])
+                    .context("Failed to drop the supplementary groups")?;
+            }
+            if let Some(gid) = gid {
+                setgid(gid).context("Failed to change the gid")?;
+            }
+            if let Some(uid) = uid {
+                setuid(uid).context("Failed to change the uid")?;
+            }
+
+            Ok(None)
+        }
+    }
+}
+
+fn remove_links(links: &[PathBuf]) {
+    for link in links.iter() {
+        let _ = std::fs::remove_file(link);
+    }
+}
+
+/// The owner and mode of the chroot directory, restored when the VM exits.
+struct RootOwner {
+    uid: Uid,
+    gid: Gid,
+    mode: u32,
+}
+
+impl RootOwner {
+    fn restore(&self, root: &Path) {
+        let _ = chown(root, Some(self.uid), Some(self.gid));
+        let _ = std::fs::set_permissions(root, Permissions::from_mode(self.mode));
+    }
+}
+
+/// Let the VMM running as `gid` create its sockets in the chroot directory,
+/// which is owned by root: its group gets write access, and the sticky bit
+/// keeps the VMM from renaming or removing the files it does not own, such as
+/// the linked kernel, initrd and drives.
+fn share_root(root: &Path, gid: Gid) -> Result<RootOwner> {
+    let metadata = std::fs::metadata(root)?;
+    let root_owner = RootOwner {
+        uid: Uid::from_raw(metadata.uid()),
+        gid: Gid::from_raw(metadata.gid()),
+        mode: metadata.mode() & 0o7777,
+    };
+    chown(root, Some(Uid::from_raw(0)), Some(gid))
+        .context("Failed to change the owner of the chroot")?;
+    std::fs::set_permissions(
+        root,
+        Permissions::from_mode(root_owner.mode | SHARED_ROOT_MODE),
+    )
+    .context("Failed to change the mode of the chroot")?;
+    Ok(root_owner)
+}
+
+/// Link the files used by the VM into the chroot, and rewrite `args` to use
+/// the paths in the chroot. Returns the host paths to remove on exit.
+fn link_files(
+    args: &mut DBSArgs,
+    root: &Path,
+    generated: &[&Path],
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+) -> Result<Vec<PathBuf>> {
+    let mut jail = JailFiles {
+        root,
+        generated,
+        uid,
+        gid,
+        links: vec![],
+        names: HashSet::new(),
+    };
+    if let Err(e) = jail.add_files(args) {
+        remove_links(&jail.links);
+        return Err(e);
+    }
+
+    Ok(jail.links)
+}
+
+struct JailFiles<'a> {
+    root: &'a Path,
+    /// directories of the files generated by dbs-cli
+    generated: &'a [&'a Path],
+    /// the owner of the VMM
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+    /// host paths of the files put in the chroot
+    links: Vec<PathBuf>,
+    /// names used in the chroot
+    names: HashSet<String>,
+}
+
+impl JailFiles<'_> {
+    fn add_files(&mut self, args: &mut DBSArgs) -> Result<()> {
+        let boot_args = &mut args.boot_args;
+        if let Some(kernel_path) = boot_args.kernel_path.as_mut() {
+            *kernel_path = self.link(kernel_path, "vmlinux", true)?;
+        }
+        if let Some(initrd_path) = boot_args.initrd_path.as_mut() {
+            *initrd_path = self.link(initrd_path, "initrd", true)?;
+        }
+        if let Some(rootfs) = boot_args.rootfs_args.rootfs.as_mut() {
+            *rootfs = self.link(rootfs, "drive-rootfs", false)?;
+        }
+
+        let create_args = &mut args.create_args;
+        for drive in create_args.drives.iter_mut() {
+            drive.path = self.link(&drive.path, &format!("drive-{}", drive.id), false)?;
+        }
+        for fs in create_args.fs.iter_mut() {
+            fs.sock_path = self.link(&fs.sock_path, &format!("fs-{}.sock", fs.tag), false)?;
+        }
+
+        // sockets created by the VMM
+        if let Some(vsock) = create_args.vsock.as_mut() {
+            vsock.path = self.socket(&vsock.path)?;
+        }
+        if create_args.serial_path != "stdio" {
+            create_args.serial_path = self.socket(&create_args.serial_path)?;
+        }
+        if !args.api_sock_path.is_empty() {
+            args.api_sock_path = self.socket(&args.api_sock_path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Hard link `path` as `/<name>` in the chroot. The files only read can
+    /// be copied instead when they are on another filesystem.
+    fn link(&mut self, path: &str, name: &str, copy_allowed: bool) -> Result<String> {
+        let target = self.reserve(name)?;
+        match std::fs::hard_link(path, &target) {
+            Ok(()) => {}
+            Err(e) if copy_allowed && e.raw_os_error() == Some(libc::EXDEV) => {
+                std::fs::copy(path, &target)
+                    .with_context(|| format!("Failed to copy {path:?} to {target:?}"))?;
+            }
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {
+                return Err(anyhow!(
+                    "{path:?} should be on the filesystem of the chroot {:?} to be linked in it",
+                    self.root
+                ));
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to link {path:?} to {target:?}"))
+            }
+        }
+        self.links.push(target.clone());
+
+        // created as root, they would not be writable by the VMM
+        let generated = self
+            .generated
+            .iter()
+            .any(|dir| Path::new(path).starts_with(dir));
+        if generated && (self.uid.is_some() || self.gid.is_some()) {
+            chown(&target, self.uid, self.gid)
+                .with_context(|| format!("Failed to change the owner of {target:?}"))?;
+        }
+
+        Ok(format!("/{name}"))
+    }
+
+    /// Move the socket `path`, created later by the VMM, into the chroot.
+    fn socket(&mut self, path: &str) -> Result<String> {
+        let name = Path::new(path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("invalid socket path {path:?}"))?
+            .to_string();
+        // a socket left by a previous VM
+        let target = self.root.join(&name);
+        if let Ok(metadata) = std::fs::symlink_metadata(&target) {
+            if metadata.file_type().is_socket() {
+                std::fs::remove_file(&target)?;
+            }
+        }
+        let target = self.reserve(&name)?;
+        self.links.push(target);
+
+        Ok(format!("/{name}"))
+    }
+
+    fn reserve(&mut self, name: &str) -> Result<PathBuf> {
+        if name == JAIL_PROC || !self.names.insert(name.to_string()) {
+            return Err(anyhow!(
+                "{name:?} is used for more than one file in the chroot"
+            ));
+        }
+        let target = self.root.join(name);
+        match std::fs::symlink_metadata(&target) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(target),
+            _ => Err(anyhow!("{target:?} already exists in the chroot")),
+        }
+    }
+}
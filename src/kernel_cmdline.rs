@@ -0,0 +1,184 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+
+use crate::cli_instance::CliInstance;
+use crate::parser::DBSArgs;
+use crate::rootfs::detect_layout;
+
+/// COMMAND_LINE_SIZE of the guest kernel, including the trailing NUL.
+const CMDLINE_MAX_SIZE: usize = 2048;
+/// The room taken by the `virtio_mmio.device=<size>@<address>:<irq>`
+/// parameter that dragonball appends for each virtio device.
+const VIRTIO_MMIO_PARAM_SIZE: usize = 48;
+/// The kernel parameters that are meant to be given more than once.
+const REPEATABLE_KEYS: [&str; 1] = ["console"];
+/// The legacy serial port used by dragonball for both the stdio and the socket console.
+const SERIAL_CONSOLE: &str = "ttyS0";
+/// The root drive is always the first virtio-blk device of the guest.
+const ROOT_DISK: &str = "/dev/vda";
+/// Used when the layout of the root drive is unknown, as in kata rootfs images.
+const DEFAULT_ROOT_DEVICE: &str = "/dev/vda1";
+
+/// The kernel command line assembled from the boot arguments.
+pub struct KernelCmdline {
+    pub cmdline: String,
+    /// Non-fatal problems found while building the command line
+    pub warnings: Vec<String>,
+}
+
+impl KernelCmdline {
+    /// Build the kernel command line from `--boot-args`, the serial console,
+    /// the root drive and `--append-boot-args`, in that order.
+    ///
+    /// `console=`, `root=` and `rootfstype=` are only derived when they are not
+    /// given explicitly, `console=` from the serial console of `--serial-path`,
+    /// `root=` and `rootfstype=` from the layout of the root drive image. A
+    /// warning is emitted when the given ones contradict them.
+    pub fn build(args: &DBSArgs) -> Result<Self> {
+        let mut params: Vec<String> = args
+            .boot_args
+            .boot_args
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+        let appended: Vec<String> = args
+            .boot_args
+            .append_boot_args
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+        let is_given = |key: &str| {
+            params
+                .iter()
+                .chain(appended.iter())
+                .any(|param| param_key(param) == key)
+        };
+
+        let mut warnings = vec![];
+        let mut derived = vec![];
+        if !is_given("console") {
+            derived.push(format!("console={SERIAL_CONSOLE}"));
+        } else if !given_values(&params, &appended, "console")
+            .any(|console| console.split(',').next() == Some(SERIAL_CONSOLE))
+        {
+            warnings.push(format!(
+                "console={SERIAL_CONSOLE} is not given, the guest messages will not reach the serial console of serial_path"
+            ));
+        }
+        if let Some(root_drive) = root_drive(args) {
+            let layout = detect_layout(&root_drive).ok().flatten();
+            let root_device = match layout {
+                Some(layout) => match layout.partition {
+                    Some(partition) => format!("{ROOT_DISK}{partition}"),
+                    None => ROOT_DISK.to_string(),
+                },
+                None => {
+                    warnings.push(format!(
+                        "no known filesystem found in the root drive {root_drive:?}, assuming {DEFAULT_ROOT_DEVICE}"
+                    ));
+                    DEFAULT_ROOT_DEVICE.to_string()
+                }
+            };
+            let fs_type = layout.map(|layout| layout.fs_type.as_str());
+
+            match given_value(&params, &appended, "root") {
+                None => derived.push(format!("root={root_device}")),
+                Some(root) if layout.is_some() && root != root_device => warnings.push(format!(
+                    "root={root} is given, but the root filesystem of {root_drive:?} is {root_device}"
+                )),
+                Some(_) => {}
+            }
+            if let Some(fs_type) = fs_type {
+                match given_value(&params, &appended, "rootfstype") {
+                    None => derived.push(format!("rootfstype={fs_type}")),
+                    Some(given) if given != fs_type => warnings.push(format!(
+                        "rootfstype={given} is given, but the root filesystem of {root_drive:?} is {fs_type}"
+                    )),
+                    Some(_) => {}
+                }
+            }
+        }
+
+        params.extend(derived);
+        params.extend(appended);
+
+        warnings.extend(
+            duplicate_keys(&params)
+                .into_iter()
+                .map(|key| format!("the kernel parameter {key:?} is given more than once")),
+        );
+
+        let cmdline = params.join(" ");
+        let appended_by_vmm = virtio_devices(args) * VIRTIO_MMIO_PARAM_SIZE;
+        if cmdline.len() + appended_by_vmm >= CMDLINE_MAX_SIZE {
+            return Err(anyhow!(
+                "the kernel command line is {} bytes long and dragonball appends up to {appended_by_vmm} bytes for the virtio devices, but the kernel accepts at most {} bytes",
+                cmdline.len(),
+                CMDLINE_MAX_SIZE - 1
+            ));
+        }
+
+        Ok(KernelCmdline { cmdline, warnings })
+    }
+}
+
+/// The image of the root drive on the host, `None` without a root drive.
+fn root_drive(args: &DBSArgs) -> Option<PathBuf> {
+    CliInstance::block_devices(args)
+        .into_iter()
+        .find(|info| info.is_root_device)
+        .map(|info| info.path_on_host)
+}
+
+/// The number of virtio devices of the VM, including the scratch drive added
+/// when the VM is created.
+fn virtio_devices(args: &DBSArgs) -> usize {
+    let create_args = &args.create_args;
+    CliInstance::block_devices(args).len()
+        + usize::from(args.boot_args.rootfs_args.scratch_size > 0)
+        + usize::from(create_args.vsock.is_some())
+        + create_args.net.len()
+        + create_args.fs.len()
+}
+
+/// The value of the last occurrence of `key`, as the kernel keeps the last one.
+fn given_value<'a>(params: &'a [String], appended: &'a [String], key: &'a str) -> Option<&'a str> {
+    given_values(params, appended, key).next_back()
+}
+
+fn given_values<'a>(
+    params: &'a [String],
+    appended: &'a [String],
+    key: &'a str,
+) -> impl DoubleEndedIterator<Item = &'a str> {
+    params
+        .iter()
+        .chain(appended.iter())
+        .filter_map(|param| param.split_once('='))
+        .filter(move |(param_key, _)| *param_key == key)
+        .map(|(_, value)| value)
+}
+
+fn param_key(param: &str) -> &str {
+    param.split_once('=').map_or(param, |(key, _)| key)
+}
+
+fn duplicate_keys(params: &[String]) -> Vec<&str> {
+    let mut seen = HashSet::new();
+    let mut duplicates = vec![];
+    for key in params
+        .iter()
+        .map(|param| param_key(param))
+        .filter(|key| !REPEATABLE_KEYS.contains(key))
+    {
+        if !seen.insert(key) && !duplicates.contains(&key) {
+            duplicates.push(key);
+        }
+    }
+    duplicates
+}
@@ -0,0 +1,374 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::io::{copy, BufReader, Read};
+#[cfg(target_arch = "x86_64")]
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+
+#[cfg(target_arch = "x86_64")]
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+#[cfg(target_arch = "x86_64")]
+const ELFCLASS64: u8 = 2;
+#[cfg(target_arch = "x86_64")]
+const ELFDATA2LSB: u8 = 1;
+#[cfg(target_arch = "x86_64")]
+const EM_X86_64: u16 = 62;
+
+/// The magic of the arm64 Image header, at offset 0x38.
+#[cfg(target_arch = "aarch64")]
+const ARM64_IMAGE_MAGIC: [u8; 4] = [b'A', b'R', b'M', 0x64];
+#[cfg(target_arch = "aarch64")]
+const ARM64_IMAGE_MAGIC_OFFSET: usize = 0x38;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: [u8; 6] = [0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+/// Offsets in the x86 boot protocol header of a bzImage.
+#[cfg(target_arch = "x86_64")]
+const BZIMAGE_SETUP_SECTS: usize = 0x1f1;
+#[cfg(target_arch = "x86_64")]
+const BZIMAGE_HEADER_MAGIC: usize = 0x202;
+#[cfg(target_arch = "x86_64")]
+const BZIMAGE_PAYLOAD_OFFSET: usize = 0x248;
+#[cfg(target_arch = "x86_64")]
+const BZIMAGE_PAYLOAD_LENGTH: usize = 0x24c;
+#[cfg(target_arch = "x86_64")]
+const BZIMAGE_HEADER_LEN: usize = 0x250;
+
+/// The bytes of the kernel header inspected to detect its format.
+#[cfg(target_arch = "x86_64")]
+const HEADER_LEN: usize = BZIMAGE_HEADER_LEN;
+#[cfg(target_arch = "aarch64")]
+const HEADER_LEN: usize = 64;
+
+/// The format dragonball boots on the host architecture.
+#[cfg(target_arch = "x86_64")]
+const BOOTABLE_FORMAT: KernelFormat = KernelFormat::Elf;
+#[cfg(target_arch = "aarch64")]
+const BOOTABLE_FORMAT: KernelFormat = KernelFormat::Image;
+
+/// Kernel image formats recognized by dbs-cli.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelFormat {
+    /// An uncompressed x86_64 vmlinux, the format dragonball boots on x86_64
+    #[cfg(target_arch = "x86_64")]
+    Elf,
+    /// An uncompressed arm64 Image, the format dragonball boots on aarch64
+    #[cfg(target_arch = "aarch64")]
+    Image,
+    Gzip,
+    Zstd,
+    Xz,
+    /// An x86 bzImage, embedding a compressed vmlinux
+    #[cfg(target_arch = "x86_64")]
+    BzImage,
+}
+
+/// Detect the format of the kernel image at `path`.
+pub fn detect_format<P: AsRef<Path>>(path: P) -> Result<KernelFormat> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    File::open(path)?
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut header)?;
+
+    if let Some(format) = detect_bootable(&header)? {
+        return Ok(format);
+    }
+    if header.starts_with(&GZIP_MAGIC) {
+        return Ok(KernelFormat::Gzip);
+    }
+    if header.starts_with(&ZSTD_MAGIC) {
+        return Ok(KernelFormat::Zstd);
+    }
+    if header.starts_with(&XZ_MAGIC) {
+        return Ok(KernelFormat::Xz);
+    }
+    #[cfg(target_arch = "x86_64")]
+    if header.len() >= BZIMAGE_HEADER_LEN
+        && &header[BZIMAGE_HEADER_MAGIC..BZIMAGE_HEADER_MAGIC + 4] == b"HdrS"
+    {
+        return Ok(KernelFormat::BzImage);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    let expected =
+        "a 64-bit ELF vmlinux for x86_64, a gzip, zstd or xz compressed vmlinux, or a bzImage";
+    #[cfg(target_arch = "aarch64")]
+    let expected = "an arm64 Image, or a gzip, zstd or xz compressed Image";
+    Err(anyhow!(
+        "not a kernel image dbs-cli can boot, expected {expected}"
+    ))
+}
+
+/// Detect a vmlinux, and make sure it is a 64-bit little-endian image for the
+/// host.
+#[cfg(target_arch = "x86_64")]
+fn detect_bootable(header: &[u8]) -> Result<Option<KernelFormat>> {
+    if !header.starts_with(&ELF_MAGIC) {
+        return Ok(None);
+    }
+    if header.len() < 20 {
+        return Err(anyhow!("truncated ELF header"));
+    }
+    if header[4] != ELFCLASS64 || header[5] != ELFDATA2LSB {
+        return Err(anyhow!(
+            "the ELF image is not a 64-bit little-endian vmlinux"
+        ));
+    }
+    let machine = u16::from_le_bytes([header[18], header[19]]);
+    if machine != EM_X86_64 {
+        return Err(anyhow!(
+            "the ELF image is built for machine {machine}, not for x86_64"
+        ));
+    }
+    Ok(Some(KernelFormat::Elf))
+}
+
+/// Detect an arm64 Image, loaded by dragonball through its header rather than
+/// as an ELF vmlinux.
+#[cfg(target_arch = "aarch64")]
+fn detect_bootable(header: &[u8]) -> Result<Option<KernelFormat>> {
+    let magic = header.get(ARM64_IMAGE_MAGIC_OFFSET..ARM64_IMAGE_MAGIC_OFFSET + 4);
+    Ok((magic == Some(&ARM64_IMAGE_MAGIC[..])).then_some(KernelFormat::Image))
+}
+
+/// Return the path of an uncompressed kernel for the kernel at `path`,
+/// extracting it into `dir` if the kernel is compressed or a bzImage.
+pub fn prepare_kernel(path: &Path, dir: &Path) -> Result<PathBuf> {
+    let format = detect_format(path)?;
+    if format == BOOTABLE_FORMAT {
+        return Ok(path.to_path_buf());
+    }
+
+    let extracted = dir.join("vmlinux");
+    let mut output = File::create(&extracted)?;
+    let input = File::open(path)?;
+    match format {
+        #[cfg(target_arch = "x86_64")]
+        KernelFormat::BzImage => {
+            let payload = bzimage_payload(input)?;
+            let payload_format = detect_payload(&payload)?;
+            decompress(payload_format, payload.as_slice(), &mut output)?;
+        }
+        _ => decompress(format, BufReader::new(input), &mut output)?,
+    }
+    drop(output);
+
+    let extracted_format = detect_format(&extracted)
+        .with_context(|| format!("Invalid kernel extracted from {format:?} kernel {path:?}"))?;
+    if extracted_format != BOOTABLE_FORMAT {
+        return Err(anyhow!(
+            "the {format:?} kernel {path:?} does not contain a {BOOTABLE_FORMAT:?} kernel"
+        ));
+    }
+
+    Ok(extracted)
+}
+
+/// Read the compressed vmlinux embedded in a bzImage.
+#[cfg(target_arch = "x86_64")]
+fn bzimage_payload(mut image: File) -> Result<Vec<u8>> {
+    let mut header = [0u8; BZIMAGE_HEADER_LEN];
+    image.seek(SeekFrom::Start(0))?;
+    image.read_exact(&mut header)?;
+
+    // 0 means 4 for old kernels
+    let setup_sects = match header[BZIMAGE_SETUP_SECTS] {
+        0 => 4,
+        sects => sects as u64,
+    };
+    let read_u32 =
+        |offset: usize| u32::from_le_bytes(header[offset..offset + 4].try_into().unwrap()) as u64;
+    let payload_offset = read_u32(BZIMAGE_PAYLOAD_OFFSET);
+    let payload_length = read_u32(BZIMAGE_PAYLOAD_LENGTH);
+    if payload_length == 0 {
+        return Err(anyhow!(
+            "the bzImage has no payload (boot protocol older than 2.08)"
+        ));
+    }
+
+    // the protected-mode code follows the real-mode setup sectors
+    let mut payload = Vec::with_capacity(payload_length as usize);
+    image.seek(SeekFrom::Start((setup_sects + 1) * 512 + payload_offset))?;
+    image.take(payload_length).read_to_end(&mut payload)?;
+    if payload.len() as u64 != payload_length {
+        return Err(anyhow!("the bzImage payload is truncated"));
+    }
+
+    Ok(payload)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn detect_payload(payload: &[u8]) -> Result<KernelFormat> {
+    if payload.starts_with(&GZIP_MAGIC) {
+        Ok(KernelFormat::Gzip)
+    } else if payload.starts_with(&ZSTD_MAGIC) {
+        Ok(KernelFormat::Zstd)
+    } else if payload.starts_with(&XZ_MAGIC) {
+        Ok(KernelFormat::Xz)
+    } else {
+        Err(anyhow!(
+            "the bzImage payload is not gzip, zstd or xz compressed"
+        ))
+    }
+}
+
+fn decompress<R: Read>(format: KernelFormat, input: R, output: &mut File) -> Result<()> {
+    match format {
+        KernelFormat::Gzip => copy(&mut flate2::read::GzDecoder::new(input), output),
+        // the kernel build appends the uncompressed size after the zstd frame
+        KernelFormat::Zstd => copy(
+            &mut zstd::stream::read::Decoder::new(input)?.single_frame(),
+            output,
+        ),
+        KernelFormat::Xz => copy(&mut xz2::read::XzDecoder::new(input), output),
+        _ => return Err(anyhow!("{format:?} is not a compression format")),
+    }
+    .with_context(|| format!("Failed to decompress the {format:?} kernel"))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::test_utils::{error_message, ImageDir};
+
+    /// An ELF header, padded to a full kernel header.
+    #[cfg(target_arch = "x86_64")]
+    fn elf(class: u8, data: u8, machine: u16) -> Vec<u8> {
+        let mut header = vec![0u8; HEADER_LEN];
+        header[..4].copy_from_slice(&ELF_MAGIC);
+        header[4] = class;
+        header[5] = data;
+        header[18..20].copy_from_slice(&machine.to_le_bytes());
+        header
+    }
+
+    /// The header of an uncompressed kernel dragonball boots.
+    #[cfg(target_arch = "x86_64")]
+    fn bootable() -> Vec<u8> {
+        elf(ELFCLASS64, ELFDATA2LSB, EM_X86_64)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn bootable() -> Vec<u8> {
+        let mut header = vec![0u8; HEADER_LEN];
+        header[ARM64_IMAGE_MAGIC_OFFSET..ARM64_IMAGE_MAGIC_OFFSET + 4]
+            .copy_from_slice(&ARM64_IMAGE_MAGIC);
+        header
+    }
+
+    #[test]
+    fn test_detect_format() {
+        let dir = ImageDir::new();
+
+        let kernel = dir.image("kernel", &bootable());
+        assert_eq!(detect_format(kernel).unwrap(), BOOTABLE_FORMAT);
+
+        let gzip = dir.image("kernel.gz", &[&GZIP_MAGIC[..], &[8, 0]].concat());
+        assert_eq!(detect_format(gzip).unwrap(), KernelFormat::Gzip);
+
+        let zstd = dir.image("kernel.zst", &ZSTD_MAGIC);
+        assert_eq!(detect_format(zstd).unwrap(), KernelFormat::Zstd);
+
+        let xz = dir.image("kernel.xz", &XZ_MAGIC);
+        assert_eq!(detect_format(xz).unwrap(), KernelFormat::Xz);
+
+        assert!(
+            error_message(detect_format(dir.image("initrd.cpio", b"070701")))
+                .starts_with("not a kernel image")
+        );
+        assert!(
+            error_message(detect_format(dir.image("empty", b""))).starts_with("not a kernel image")
+        );
+        assert!(detect_format(dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_prepare_compressed_kernel() {
+        let dir = ImageDir::new();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(&bootable()).unwrap();
+        let gzip = dir.image("kernel.gz", &encoder.finish().unwrap());
+
+        let extracted = prepare_kernel(&gzip, dir.path()).unwrap();
+        assert_eq!(std::fs::read(extracted).unwrap(), bootable());
+
+        // the compressed kernel is not bootable
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        encoder.write_all(&ZSTD_MAGIC).unwrap();
+        let gzip = dir.image("nested.gz", &encoder.finish().unwrap());
+        assert!(prepare_kernel(&gzip, dir.path()).is_err());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_detect_x86_64_formats() {
+        let dir = ImageDir::new();
+
+        let mut header = vec![0u8; BZIMAGE_HEADER_LEN];
+        header[BZIMAGE_HEADER_MAGIC..BZIMAGE_HEADER_MAGIC + 4].copy_from_slice(b"HdrS");
+        let bzimage = dir.image("bzImage", &header);
+        assert_eq!(detect_format(bzimage).unwrap(), KernelFormat::BzImage);
+
+        // too short to hold a bzImage header
+        header.truncate(BZIMAGE_HEADER_MAGIC + 4);
+        assert!(
+            error_message(detect_format(dir.image("short-bzImage", &header)))
+                .starts_with("not a kernel image")
+        );
+
+        assert_eq!(
+            error_message(detect_format(dir.image("truncated", &bootable()[..16]))),
+            "truncated ELF header"
+        );
+        // ELFCLASS32
+        assert_eq!(
+            error_message(detect_format(
+                dir.image("32-bit", &elf(1, ELFDATA2LSB, EM_X86_64))
+            )),
+            "the ELF image is not a 64-bit little-endian vmlinux"
+        );
+        // ELFDATA2MSB
+        assert_eq!(
+            error_message(detect_format(
+                dir.image("big-endian", &elf(ELFCLASS64, 2, EM_X86_64))
+            )),
+            "the ELF image is not a 64-bit little-endian vmlinux"
+        );
+        // EM_AARCH64
+        assert_eq!(
+            error_message(detect_format(
+                dir.image("aarch64", &elf(ELFCLASS64, ELFDATA2LSB, 183))
+            )),
+            "the ELF image is built for machine 183, not for x86_64"
+        );
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_detect_arm64_formats() {
+        let dir = ImageDir::new();
+
+        // an ELF vmlinux is not loaded on aarch64
+        let mut vmlinux = vec![0u8; HEADER_LEN];
+        vmlinux[..4].copy_from_slice(&[0x7f, b'E', b'L', b'F']);
+        assert!(error_message(detect_format(dir.image("vmlinux", &vmlinux)))
+            .starts_with("not a kernel image"));
+
+        // too short to hold the Image magic
+        let header = &bootable()[..ARM64_IMAGE_MAGIC_OFFSET + 2];
+        assert!(
+            error_message(detect_format(dir.image("short-Image", header)))
+                .starts_with("not a kernel image")
+        );
+    }
+}
@@ -2,32 +2,69 @@
 
 extern crate slog_term;
 
-use std::str::FromStr;
 use std::sync::Mutex;
 
 use anyhow::Result;
-use api_client::run_api_client;
-use clap::Parser;
+use api_client::{run_api_client, run_status_client};
+use daemon::daemonize;
+use jailer::close_inherited_fds;
 use slog::Drain;
 use slog::*;
 use slog_scope::set_global_logger;
 
+use parser::load_args;
+use parser::print_dry_run;
 use parser::run_with_cli;
 use parser::Commands;
-use parser::DBSArgs;
 
+mod affinity;
 mod api_client;
 mod api_server;
+mod cgroup;
 mod cli_instance;
+mod daemon;
+mod ephemeral;
+mod error;
+mod exit_code;
+mod file_lock;
+mod image_lock;
+mod instance_dir;
+mod jailer;
+mod kernel_cmdline;
+mod kernel_image;
+mod memory;
+mod numa;
 mod parser;
+mod rootfs;
+mod seccomp;
+mod signal;
+#[cfg(test)]
+mod test_utils;
+mod validation;
+mod vm_spec;
 mod vmm_comm_trait;
+mod vsock;
 
 fn main() -> Result<()> {
-    let args: DBSArgs = DBSArgs::parse();
+    // a bad config file is an invalid argument, as clap errors are
+    let args = load_args().unwrap_or_else(|e| exit_on_error(e, exit_code::STARTUP_ERROR));
     match args.command {
-        Some(Commands::Create) => {
+        Some(Commands::Create { dry_run: true }) => {
+            print_dry_run(args).unwrap_or_else(|e| exit_on_error(e, exit_code::STARTUP_ERROR));
+        }
+        Some(Commands::Create { dry_run: false }) => {
+            if args.create_args.chroot.is_some() {
+                close_inherited_fds()
+                    .unwrap_or_else(|e| exit_on_error(e, exit_code::STARTUP_ERROR));
+            }
+            // the daemon is forked before any thread is spawned
+            let daemon = if args.create_args.daemon {
+                Some(daemonize().unwrap_or_else(|e| exit_on_error(e, exit_code::STARTUP_ERROR)))
+            } else {
+                None
+            };
             let log_file = &args.log_file;
-            let log_level = Level::from_str(&args.log_level).unwrap();
+            let log_level = Level::from(args.log_level);
 
             let file = std::fs::OpenOptions::new()
                 .truncate(true)
@@ -43,10 +80,26 @@ fn main() -> Result<()> {
             );
 
             let _guard = set_global_logger(root);
-            run_with_cli(args)?;
+            let exit_code = match run_with_cli(args, daemon) {
+                Ok(exit_code) => exit_code,
+                Err(e) => {
+                    eprintln!("Error: {e:?}");
+                    error!(slog_scope::logger(), "failed to create the VM";
+                        "error" => format!("{e:#}"));
+                    exit_code::from_error(&e, exit_code::STARTUP_ERROR)
+                }
+            };
+            info!(slog_scope::logger(), "dbs-cli exits";
+                "exit_code" => exit_code,
+                "reason" => exit_code::reason(exit_code));
+            // the guards of the VM are already dropped by run_with_cli
+            std::process::exit(exit_code);
         }
         Some(Commands::Update) => {
-            run_api_client(args)?;
+            run_api_client(args).unwrap_or_else(|e| exit_on_error(e, exit_code::VMM_ERROR));
+        }
+        Some(Commands::Status) => {
+            run_status_client(args).unwrap_or_else(|e| exit_on_error(e, exit_code::VMM_ERROR));
         }
         _ => {
             panic!("Invalid command provided for dbs-cli.");
@@ -54,3 +107,10 @@ fn main() -> Result<()> {
     }
     Ok(())
 }
+
+/// Print the error and exit with its exit code, `fatal` unless the error
+/// tells otherwise.
+fn exit_on_error(e: anyhow::Error, fatal: i32) -> ! {
+    eprintln!("Error: {e:?}");
+    std::process::exit(exit_code::from_error(&e, fatal));
+}
@@ -0,0 +1,202 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use nix::sys::statfs::{statfs, HUGETLBFS_MAGIC};
+
+use crate::parser::DBSArgs;
+
+const HUGEPAGES_DIR: &str = "/sys/kernel/mm/hugepages";
+/// Populate the pages of a range for writing, without changing their content
+/// (Linux 5.14)
+const MADV_POPULATE_WRITE: libc::c_int = 23;
+
+/// Check that `mem_file_path` is on a hugetlbfs mount, and that the host has
+/// enough free hugepages of its page size for the guest memory.
+pub fn check_hugepages(args: &DBSArgs) -> Result<()> {
+    let create_args = &args.create_args;
+    let mem_file_path = Path::new(&create_args.mem_file_path);
+    // the memory file may be created by the VMM
+    let mount_path = if mem_file_path.exists() {
+        mem_file_path
+    } else {
+        match mem_file_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        }
+    };
+    let stat = statfs(mount_path)
+        .with_context(|| format!("Failed to get the filesystem of {mount_path:?}"))?;
+    if stat.filesystem_type() != HUGETLBFS_MAGIC {
+        return Err(anyhow!(
+            "mem_file_path {mem_file_path:?} is not on a hugetlbfs mount"
+        ));
+    }
+
+    // the block size of a hugetlbfs mount is its page size
+    let page_size_kib = stat.block_size() as u64 / 1024;
+    let mem_size_kib = create_args.mem_size as u64 * 1024;
+    let needed = mem_size_kib.checked_div(page_size_kib).unwrap_or(0);
+    if needed == 0 || needed * page_size_kib != mem_size_kib {
+        return Err(anyhow!(
+            "mem_size ({} MiB) should be a multiple of the hugepage size ({page_size_kib} KiB) of {mount_path:?}",
+            create_args.mem_size
+        ));
+    }
+
+    let pool = Path::new(HUGEPAGES_DIR).join(format!("hugepages-{page_size_kib}kB"));
+    let read_count = |name: &str| -> Result<u64> {
+        let path = pool.join(name);
+        std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {path:?}"))?
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid hugepage count in {path:?}"))
+    };
+    // the reserved pages are free, but promised to mappings already made
+    let free = read_count("free_hugepages")?.saturating_sub(read_count("resv_hugepages")?);
+    if free < needed {
+        return Err(anyhow!(
+            "{needed} free {page_size_kib} KiB hugepages are needed for {} MiB of guest memory, but the host has {free}, see {:?}",
+            create_args.mem_size,
+            pool.join("nr_hugepages")
+        ));
+    }
+
+    Ok(())
+}
+
+/// A mapping of the guest memory in the VMM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GuestRegion {
+    pub start: usize,
+    pub len: usize,
+}
+
+impl GuestRegion {
+    pub fn contains(&self, address: usize) -> bool {
+        address >= self.start && address - self.start < self.len
+    }
+}
+
+/// The mappings of the guest memory, read from `/proc/self/maps` once the
+/// VM is started: the shared memfd mappings the VMM creates it with, or the
+/// mappings of `mem_file_path` with the hugetlbfs memory type.
+pub fn guest_memory_regions(mem_file_path: &str) -> Result<Vec<GuestRegion>> {
+    let maps =
+        std::fs::read_to_string("/proc/self/maps").context("Failed to read /proc/self/maps")?;
+    // the mapped paths are absolute
+    let mem_file_path = if mem_file_path.is_empty() {
+        PathBuf::new()
+    } else {
+        std::fs::canonicalize(mem_file_path).unwrap_or_else(|_| PathBuf::from(mem_file_path))
+    };
+
+    let regions = parse_guest_regions(&maps, &mem_file_path)?;
+    if regions.is_empty() {
+        return Err(anyhow!("no guest memory mapping found"));
+    }
+    Ok(regions)
+}
+
+/// Find the guest memory mappings in the content of `/proc/<pid>/maps`. The
+/// files of the hugetlbfs memory type are `mem_file_path`, or files in it
+/// when it is a directory.
+fn parse_guest_regions(maps: &str, mem_file_path: &Path) -> Result<Vec<GuestRegion>> {
+    let mut regions = vec![];
+    for line in maps.lines() {
+        // <start>-<end> <perms> <offset> <dev> <inode> <path>
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 || fields[1] != "rw-s" {
+            continue;
+        }
+        let path = fields[5..].join(" ");
+        let path = path.strip_suffix(" (deleted)").unwrap_or(&path);
+        let is_mem_file =
+            !mem_file_path.as_os_str().is_empty() && Path::new(path).starts_with(mem_file_path);
+        if !path.starts_with("/memfd:") && !is_mem_file {
+            continue;
+        }
+
+        let (start, end) = fields[0]
+            .split_once('-')
+            .ok_or_else(|| anyhow!("invalid mapping {line:?}"))?;
+        let start = usize::from_str_radix(start, 16)?;
+        let len = usize::from_str_radix(end, 16)? - start;
+        regions.push(GuestRegion { start, len });
+    }
+    Ok(regions)
+}
+
+/// Allocate all the pages of the guest memory, so the guest does not fault
+/// them in while it runs. Returns the number of bytes populated.
+pub fn prefault_guest_memory(regions: &[GuestRegion]) -> Result<u64> {
+    let mut populated = 0;
+    for region in regions.iter() {
+        // SAFETY: the range is a mapping of the process, and populating it
+        // leaves its content unchanged.
+        let ret = unsafe {
+            libc::madvise(
+                region.start as *mut libc::c_void,
+                region.len,
+                MADV_POPULATE_WRITE,
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error()).context(
+                "Failed to prefault the guest memory, MADV_POPULATE_WRITE needs Linux 5.14",
+            );
+        }
+        populated += region.len as u64;
+    }
+    Ok(populated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAPS: &str = "\
+55d0c0a00000-55d0c0c00000 r-xp 00000000 fd:01 393218                     /usr/bin/dbs-cli
+7f2a00000000-7f2a40000000 rw-s 00000000 00:01 1035                       /memfd:shmem (deleted)
+7f2b00000000-7f2b20000000 rw-s 00000000 00:2f 21                         /dev/hugepages/vm1/mem (deleted)
+7f2c00000000-7f2c00001000 rw-s 00000000 00:2f 22                         /dev/hugepages/other
+7f2d00000000-7f2d00200000 rw-p 00000000 00:00 0                          [heap]
+";
+
+    #[test]
+    fn test_parse_guest_regions_memfd() {
+        let regions = parse_guest_regions(MAPS, Path::new("")).unwrap();
+        assert_eq!(
+            regions,
+            vec![GuestRegion {
+                start: 0x7f2a00000000,
+                len: 0x40000000
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_guest_regions_hugetlbfs() {
+        let hugetlbfs_region = GuestRegion {
+            start: 0x7f2b00000000,
+            len: 0x20000000,
+        };
+
+        // a file in the mem_file_path directory
+        let regions = parse_guest_regions(MAPS, Path::new("/dev/hugepages/vm1")).unwrap();
+        assert!(regions.contains(&hugetlbfs_region));
+        assert_eq!(regions.len(), 2);
+
+        // the mem_file_path file itself, removed once mapped
+        let regions = parse_guest_regions(MAPS, Path::new("/dev/hugepages/vm1/mem")).unwrap();
+        assert!(regions.contains(&hugetlbfs_region));
+        assert_eq!(regions.len(), 2);
+
+        // the other files of the mount are not guest memory
+        let regions = parse_guest_regions(MAPS, Path::new("/dev/hugepages/vm")).unwrap();
+        assert_eq!(regions.len(), 1);
+    }
+}
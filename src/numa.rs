@@ -0,0 +1,198 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::affinity::{parse_id_list, CpuAffinity};
+use crate::memory::GuestRegion;
+use crate::parser::args::NumaMode;
+use crate::parser::DBSArgs;
+
+const NODE_DIR: &str = "/sys/devices/system/node";
+const CPU_DIR: &str = "/sys/devices/system/cpu";
+/// `--numa-nodes` value taking the nodes of the vcpu host CPUs
+const VCPU_AFFINITY_NODES: &str = "vcpu-affinity";
+
+/// Modes of mbind(2)
+const MPOL_PREFERRED: libc::c_int = 1;
+const MPOL_BIND: libc::c_int = 2;
+/// Move the pages already allocated to the nodes of the policy
+const MPOL_MF_MOVE: libc::c_uint = 1 << 1;
+
+/// The NUMA policy of the guest memory.
+#[derive(Clone, Debug)]
+pub struct NumaPolicy {
+    mode: NumaMode,
+    nodes: BTreeSet<usize>,
+}
+
+impl NumaPolicy {
+    /// Resolve `--numa-policy` and `--numa-nodes`, and check that the nodes
+    /// exist on the host. Returns `None` with the default policy.
+    pub fn from_args(args: &DBSArgs) -> Result<Option<Self>> {
+        let mode = args.create_args.numa_policy;
+        let nodes = match parse_args(args)? {
+            None => return Ok(None),
+            Some(Some(nodes)) => nodes,
+            Some(None) => vcpu_nodes(args)?,
+        };
+
+        for node in nodes.iter() {
+            if !Path::new(NODE_DIR).join(format!("node{node}")).exists() {
+                return Err(anyhow!("NUMA node {node} does not exist on the host"));
+            }
+        }
+        check_preferred(mode, &nodes)?;
+
+        Ok(Some(NumaPolicy { mode, nodes }))
+    }
+
+    /// Check `--numa-policy` and `--numa-nodes` without looking at the host.
+    pub fn check_args(args: &DBSArgs) -> Result<()> {
+        if let Some(None) = parse_args(args)? {
+            if args.create_args.vcpu_affinity.is_none() {
+                return Err(anyhow!(
+                    "numa_nodes {VCPU_AFFINITY_NODES} needs vcpu_affinity"
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Set the memory policy of the guest memory. The pages the VM has
+    /// already touched while it was started are moved to the nodes.
+    pub fn apply(&self, regions: &[GuestRegion]) -> Result<()> {
+        let mode = match self.mode {
+            NumaMode::Default => return Ok(()),
+            NumaMode::Bind => MPOL_BIND,
+            NumaMode::Preferred => MPOL_PREFERRED,
+        };
+        let bits = libc::c_ulong::BITS as usize;
+        let max_node = self.nodes.iter().max().copied().unwrap_or(0);
+        let mut mask: Vec<libc::c_ulong> = vec![0; max_node / bits + 1];
+        for node in self.nodes.iter() {
+            mask[node / bits] |= 1 << (node % bits);
+        }
+
+        for region in regions.iter() {
+            // SAFETY: the range is a mapping of the process, the mask holds
+            // `mask.len() * bits` bits, the kernel reads one less than the
+            // given maximum.
+            let ret = unsafe {
+                libc::syscall(
+                    libc::SYS_mbind,
+                    region.start,
+                    region.len,
+                    mode,
+                    mask.as_ptr(),
+                    mask.len() * bits + 1,
+                    MPOL_MF_MOVE,
+                )
+            };
+            if ret < 0 {
+                return Err(std::io::Error::last_os_error())
+                    .context("Failed to set the NUMA policy of the guest memory");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The resident guest memory on each NUMA node, in bytes, read from
+/// `/proc/self/numa_maps`.
+pub fn memory_per_node(regions: &[GuestRegion]) -> Result<BTreeMap<usize, u64>> {
+    let numa_maps = std::fs::read_to_string("/proc/self/numa_maps")
+        .context("Failed to read /proc/self/numa_maps")?;
+
+    let mut memory = BTreeMap::new();
+    for line in numa_maps.lines() {
+        // <start> <policy> <fields>...
+        let start = line
+            .split_whitespace()
+            .next()
+            .and_then(|start| usize::from_str_radix(start, 16).ok());
+        if !matches!(start, Some(start) if regions.iter().any(|region| region.contains(start))) {
+            continue;
+        }
+
+        let mut pages = vec![];
+        let mut page_size = 0;
+        for field in line.split_whitespace() {
+            if let Some(size) = field.strip_prefix("kernelpagesize_kB=") {
+                page_size = size.parse::<u64>().unwrap_or(0) * 1024;
+            } else if let Some((node, count)) = field
+                .strip_prefix('N')
+                .and_then(|field| field.split_once('='))
+            {
+                if let (Ok(node), Ok(count)) = (node.parse::<usize>(), count.parse::<u64>()) {
+                    pages.push((node, count));
+                }
+            }
+        }
+        for (node, count) in pages {
+            *memory.entry(node).or_insert(0) += count * page_size;
+        }
+    }
+    Ok(memory)
+}
+
+/// Parse `--numa-policy` and `--numa-nodes`: `None` with the default policy,
+/// else the nodes, or `None` for the nodes of the vcpu affinity.
+fn parse_args(args: &DBSArgs) -> Result<Option<Option<BTreeSet<usize>>>> {
+    let create_args = &args.create_args;
+    let mode = create_args.numa_policy;
+    match (mode, &create_args.numa_nodes) {
+        (NumaMode::Default, None) => Ok(None),
+        (NumaMode::Default, Some(_)) => Err(anyhow!(
+            "numa_nodes has no effect when numa_policy is default, use bind or preferred"
+        )),
+        (_, None) => Err(anyhow!("numa_policy bind and preferred need numa_nodes")),
+        (_, Some(nodes)) if nodes == VCPU_AFFINITY_NODES => Ok(Some(None)),
+        (_, Some(nodes)) => {
+            let nodes = parse_id_list(nodes, "NUMA node")?.into_iter().collect();
+            check_preferred(mode, &nodes)?;
+            Ok(Some(Some(nodes)))
+        }
+    }
+}
+
+fn check_preferred(mode: NumaMode, nodes: &BTreeSet<usize>) -> Result<()> {
+    if mode == NumaMode::Preferred && nodes.len() != 1 {
+        return Err(anyhow!(
+            "numa_policy preferred takes a single node, got {nodes:?}"
+        ));
+    }
+    Ok(())
+}
+
+/// The nodes of the host CPUs the vcpus are pinned to.
+fn vcpu_nodes(args: &DBSArgs) -> Result<BTreeSet<usize>> {
+    let affinity = CpuAffinity::from_args(args)?;
+    let cpus: Vec<usize> = affinity.vcpu_host_cpus().collect();
+    if cpus.is_empty() {
+        return Err(anyhow!(
+            "numa_nodes {VCPU_AFFINITY_NODES} needs vcpu_affinity"
+        ));
+    }
+    cpus.into_iter().map(cpu_node).collect()
+}
+
+/// The node of a host CPU, from the `node<N>` link in its sysfs directory.
+fn cpu_node(cpu: usize) -> Result<usize> {
+    let cpu_dir = Path::new(CPU_DIR).join(format!("cpu{cpu}"));
+    std::fs::read_dir(&cpu_dir)
+        .with_context(|| format!("Failed to find the NUMA node of CPU {cpu}"))?
+        .filter_map(|entry| entry.ok())
+        .find_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("node")?
+                .parse()
+                .ok()
+        })
+        .ok_or_else(|| anyhow!("CPU {cpu} is not in any NUMA node"))
+}
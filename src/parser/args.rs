@@ -2,8 +2,11 @@
 // Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use clap::{Args, Parser, Subcommand};
+use std::str::FromStr;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use serde_derive::{Deserialize, Serialize};
+use slog::Level;
 
 /// A simple command-line tool to start DragonBall micro-VM
 #[derive(Parser, Debug, Clone)]
@@ -18,11 +21,26 @@ pub struct DBSArgs {
     #[clap(flatten)]
     pub boot_args: BootArgs,
 
+    #[clap(
+        long,
+        value_parser,
+        help = "The path of a TOML, JSON or YAML file describing the VM (command-line flags and environment variables take precedence over it)",
+        display_order = 1
+    )]
+    pub config: Option<String>,
+
     #[clap(long, value_parser, default_value = "dbs-cli.log", display_order = 1)]
     pub log_file: String,
 
-    #[clap(long, value_parser, default_value = "Info", display_order = 1)]
-    pub log_level: String,
+    #[clap(
+        long,
+        value_enum,
+        ignore_case = true,
+        default_value_t = LogLevel::Info,
+        help = "The logging level (case-insensitive)",
+        display_order = 1
+    )]
+    pub log_level: LogLevel,
 
     #[clap(
         long,
@@ -40,9 +58,143 @@ pub struct DBSArgs {
 #[derive(Subcommand, Debug, Clone)]
 pub enum Commands {
     /// Create Dragonball Instance
-    Create,
+    Create {
+        #[clap(
+            long,
+            help = "Print the resolved VM specification as JSON instead of creating the VM (/dev/kvm is not needed)"
+        )]
+        dry_run: bool,
+    },
     /// Connect to Dragonball Api Server and update the Dragonball VM (Must create a api socket when creating the Dragonball VM)
     Update,
+    /// Connect to Dragonball Api Server and print the instance state, VM configuration and attached devices as JSON
+    #[clap(alias = "inspect")]
+    Status,
+}
+
+/// Logging levels, from the most to the least severe
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    /// Critical errors only
+    Critical,
+    /// Errors
+    Error,
+    /// Warnings and errors
+    Warning,
+    /// Informational messages
+    Info,
+    /// Debugging messages
+    Debug,
+    /// Everything
+    Trace,
+}
+
+impl From<LogLevel> for Level {
+    fn from(log_level: LogLevel) -> Self {
+        match log_level {
+            LogLevel::Critical => Level::Critical,
+            LogLevel::Error => Level::Error,
+            LogLevel::Warning => Level::Warning,
+            LogLevel::Info => Level::Info,
+            LogLevel::Debug => Level::Debug,
+            LogLevel::Trace => Level::Trace,
+        }
+    }
+}
+
+/// CPU power management modes
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CpuPm {
+    /// Let the guest manage the cpu power states
+    On,
+    /// Disable cpu power management in the guest
+    Off,
+}
+
+impl CpuPm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CpuPm::On => "on",
+            CpuPm::Off => "off",
+        }
+    }
+}
+
+/// Levels of the virtual PMU exposed to the guest, e.g. to run `perf` in it
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VpmuFeature {
+    /// No vpmu
+    #[value(alias = "0")]
+    #[serde(alias = "0")]
+    Disabled,
+    /// Only the architectural counters
+    #[value(alias = "1")]
+    #[serde(alias = "1")]
+    Limited,
+    /// All the counters of the host pmu
+    #[value(alias = "2")]
+    #[serde(alias = "2")]
+    Full,
+}
+
+impl VpmuFeature {
+    /// The level as understood by dragonball
+    pub fn level(&self) -> u8 {
+        match self {
+            VpmuFeature::Disabled => 0,
+            VpmuFeature::Limited => 1,
+            VpmuFeature::Full => 2,
+        }
+    }
+}
+
+/// Seccomp filtering of the VMM and vcpu threads
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SeccompLevel {
+    /// No syscall filtering
+    None,
+    /// Log the syscalls missing from the allowlist, and let them run
+    Log,
+    /// Kill dbs-cli on a syscall missing from the allowlist
+    Kill,
+}
+
+/// NUMA policies of the guest memory
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NumaMode {
+    /// Allocate on the node of the CPU that first touches the memory
+    Default,
+    /// Only allocate on the given nodes
+    Bind,
+    /// Allocate on the given node, and on the others when it is full
+    Preferred,
+}
+
+/// Memory types backing the guest memory
+#[derive(ValueEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MemType {
+    /// Shared memory
+    Shmem,
+    /// Hugepages from a hugetlbfs mount, requires mem_file_path
+    Hugetlbfs,
+    /// Shared memory backed by transparent hugepages
+    Hugeshmem,
+}
+
+impl MemType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MemType::Shmem => "shmem",
+            MemType::Hugetlbfs => "hugetlbfs",
+            MemType::Hugeshmem => "hugeshmem",
+        }
+    }
 }
 
 /// CPU related configurations
@@ -51,6 +203,7 @@ pub struct CpuTopologyArgs {
     #[clap(
         long,
         value_parser,
+        env = "DBS_THREADS_PER_CORE",
         default_value_t = 1,
         help = "Threads per core to indicate hyper-threading is enabled or not",
         display_order = 1
@@ -60,6 +213,7 @@ pub struct CpuTopologyArgs {
     #[clap(
         long,
         value_parser,
+        env = "DBS_CORES_PER_DIE",
         default_value_t = 1,
         help = "Cores per die to guide guest cpu topology init",
         display_order = 1
@@ -69,6 +223,7 @@ pub struct CpuTopologyArgs {
     #[clap(
         long,
         value_parser,
+        env = "DBS_DIES_PER_SOCKET",
         default_value_t = 1,
         help = "Dies per socket to guide guest cpu topology",
         display_order = 1
@@ -78,6 +233,7 @@ pub struct CpuTopologyArgs {
     #[clap(
         long,
         value_parser,
+        env = "DBS_SOCKETS",
         default_value_t = 1,
         help = "The number of sockets",
         display_order = 1
@@ -92,7 +248,8 @@ pub struct RootfsArgs {
         short,
         long,
         value_parser,
-        help = "The path of rootfs file",
+        env = "DBS_ROOTFS",
+        help = "The path of rootfs file (Optional when booting from an initrd)",
         display_order = 4
     )]
     pub rootfs: Option<String>,
@@ -100,6 +257,7 @@ pub struct RootfsArgs {
     #[clap(
         long,
         value_parser,
+        env = "DBS_IS_ROOT",
         default_value_t = true,
         help = "Decide the device to be the root boot device or not [default: true]",
         display_order = 5
@@ -109,11 +267,41 @@ pub struct RootfsArgs {
     #[clap(
         long,
         value_parser,
+        env = "DBS_IS_READ_ONLY",
         default_value_t = false,
         help = "The driver opened in read-only or not [default: false]",
         display_order = 6
     )]
     pub is_read_only: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_EPHEMERAL",
+        default_value_t = false,
+        help = "Boot from a private copy-on-write clone of rootfs, deleted on exit, so the image itself is never written [default: false]",
+        display_order = 7
+    )]
+    pub ephemeral: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_EPHEMERAL_DIR",
+        help = "The directory holding the ephemeral rootfs clone, on the same filesystem as rootfs for a reflink [default: the directory of rootfs]",
+        display_order = 8
+    )]
+    pub ephemeral_dir: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_SCRATCH_SIZE",
+        default_value_t = 0,
+        help = "The size in MiB of an empty writable scratch drive added next to rootfs and deleted on exit, 0 for none [default: 0]",
+        display_order = 9
+    )]
+    pub scratch_size: u64,
 }
 
 /// Configurations used for creating a VM.
@@ -124,6 +312,7 @@ pub struct CreateArgs {
         short = 'C',
         long,
         value_parser,
+        env = "DBS_VCPU",
         default_value_t = 1,
         help = "The number of vcpu to start",
         display_order = 1
@@ -132,6 +321,7 @@ pub struct CreateArgs {
     #[clap(
         long,
         value_parser,
+        env = "DBS_MAX_VCPU",
         default_value_t = 1,
         help = "The max number of vpu can be added",
         display_order = 1
@@ -140,19 +330,25 @@ pub struct CreateArgs {
     #[clap(
         long,
         value_parser,
-        default_value = "on",
+        env = "DBS_CPU_PM",
+        value_enum,
+        ignore_case = true,
+        default_value_t = CpuPm::On,
         help = "The cpu power management",
         display_order = 1
     )]
-    pub cpu_pm: String,
+    pub cpu_pm: CpuPm,
     #[clap(
         long,
         value_parser,
-        default_value_t = 0,
-        help = "vpmu support level",
+        env = "DBS_VPMU_FEATURE",
+        value_enum,
+        ignore_case = true,
+        default_value_t = VpmuFeature::Disabled,
+        help = "vpmu support level, the host must have a pmu usable by kvm to enable it",
         display_order = 1
     )]
-    pub vpmu_feature: u8,
+    pub vpmu_feature: VpmuFeature,
     #[clap(flatten)]
     pub cpu_topology: CpuTopologyArgs,
 
@@ -160,14 +356,18 @@ pub struct CreateArgs {
     #[clap(
         long,
         value_parser,
-        default_value = "shmem",
-        help = "Memory type that can be either hugetlbfs or shmem, default is shmem",
+        env = "DBS_MEM_TYPE",
+        value_enum,
+        ignore_case = true,
+        default_value_t = MemType::Shmem,
+        help = "Memory type backing the guest memory",
         display_order = 2
     )]
-    pub mem_type: String,
+    pub mem_type: MemType,
     #[clap(
         long,
         value_parser,
+        env = "DBS_MEM_FILE_PATH",
         default_value = "",
         help = "Memory file path",
         display_order = 2
@@ -177,35 +377,328 @@ pub struct CreateArgs {
         short,
         long,
         value_parser,
+        env = "DBS_MEM_SIZE",
         default_value_t = 128,
         help = "The memory size in Mib",
         display_order = 2
     )]
     pub mem_size: usize,
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_MEM_PREFAULT",
+        default_value_t = false,
+        help = "Allocate all the guest memory when the VM starts, for a predictable latency [default: false]",
+        display_order = 2
+    )]
+    pub mem_prefault: bool,
 
     // The serial path used to communicate with VM
     #[clap(
         short,
         long,
         value_parser,
+        env = "DBS_SERIAL_PATH",
         default_value = "stdio",
         help = "The serial path used to communicate with VM",
         display_order = 2
     )]
     pub serial_path: String,
 
-    // The path to a vsock socket file
-    // FIXME: add more params:
-    // cid="contextid",socket_path="somepath",gid="guest_id"
+    // The virtio-vsock device, given as `[cid=<cid>,]path=<path>` or a bare socket path
     #[clap(
         short,
         long,
         value_parser,
-        default_value = "",
-        help = "Virtio VSOCK socket path",
+        value_name = "VSOCK",
+        env = "DBS_VSOCK",
+        help = "Virtio VSOCK device in the form of [cid=<guest cid>,]path=<socket path>, a free guest cid is picked if it is not given",
         display_order = 2
     )]
-    pub vsock: String,
+    pub vsock: Option<VsockArgs>,
+
+    #[clap(
+        long = "drive",
+        value_parser,
+        value_name = "DRIVE",
+        help = "Extra block device in the form of id=<id>,path=<path>[,ro=<bool>][,root=<bool>], can be repeated",
+        display_order = 5
+    )]
+    pub drives: Vec<DriveArgs>,
+
+    #[clap(
+        long,
+        value_enum,
+        ignore_case = true,
+        env = "DBS_SECCOMP_LEVEL",
+        default_value_t = SeccompLevel::None,
+        help = "What happens when the VMM or a vcpu thread makes a syscall missing from its seccomp allowlist",
+        display_order = 6
+    )]
+    pub seccomp_level: SeccompLevel,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_SECCOMP_POLICY",
+        help = "The path of a seccompiler JSON file with \"vmm\" and \"vcpu\" filters replacing the built-in allowlists",
+        display_order = 6
+    )]
+    pub seccomp_policy: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_CHROOT",
+        help = "Run the VMM jailed in this directory, in new mount, pid, ipc and net namespaces. The kernel, initrd and drives are hard linked into it, and the sockets are created in it",
+        display_order = 7
+    )]
+    pub chroot: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_UID",
+        help = "The uid the jailed VMM runs as (needs chroot and gid)",
+        display_order = 7
+    )]
+    pub uid: Option<u32>,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_GID",
+        help = "The gid the jailed VMM runs as (needs chroot and uid)",
+        display_order = 7
+    )]
+    pub gid: Option<u32>,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_CGROUP",
+        help = "The cgroup v2 group of the VM, relative to /sys/fs/cgroup, created if missing and removed on exit if created",
+        display_order = 8
+    )]
+    pub cgroup: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_CPU_MAX",
+        help = "The cpu.max limit of the cgroup, \"<quota> [<period>]\" in microseconds or \"max\"; with update, changes it live",
+        display_order = 8
+    )]
+    pub cpu_max: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_MEMORY_MAX",
+        help = "The memory.max limit of the cgroup, in bytes or \"max\"; with update, changes it live",
+        display_order = 8
+    )]
+    pub memory_max: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        value_name = "IO_MAX",
+        help = "An io.max limit of the cgroup, \"<major>:<minor> <key>=<value>...\", can be repeated; with update, changes it live",
+        display_order = 8
+    )]
+    pub io_max: Vec<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_PIDS_MAX",
+        help = "The pids.max limit of the cgroup, a number or \"max\"; with update, changes it live",
+        display_order = 8
+    )]
+    pub pids_max: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_VCPU_AFFINITY",
+        help = "Pin vcpus to host CPUs, \"<vcpu>:<host cpu>\" pairs such as \"0:2,1:3\"; the other vcpus run on the VMM CPUs",
+        display_order = 9
+    )]
+    pub vcpu_affinity: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_VMM_AFFINITY",
+        help = "The host CPUs of the VMM, api server and set_cfg threads, a CPU list such as \"0-1,4\"",
+        display_order = 9
+    )]
+    pub vmm_affinity: Option<String>,
+
+    #[clap(
+        long,
+        value_enum,
+        ignore_case = true,
+        env = "DBS_NUMA_POLICY",
+        default_value_t = NumaMode::Default,
+        help = "The NUMA policy of the guest memory, bind or preferred needs numa_nodes",
+        display_order = 9
+    )]
+    pub numa_policy: NumaMode,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_NUMA_NODES",
+        help = "The host NUMA nodes of the guest memory, a node list such as \"0-1\", or \"vcpu-affinity\" for the nodes of the vcpu host CPUs",
+        display_order = 9
+    )]
+    pub numa_nodes: Option<String>,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_DAEMON",
+        default_value_t = false,
+        help = "Run in the background once the VM has started, needs a serial_path other than stdio [default: false]",
+        display_order = 10
+    )]
+    pub daemon: bool,
+
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_PIDFILE",
+        help = "Write the pid of dbs-cli to this file, locked while it runs and removed on exit",
+        display_order = 10
+    )]
+    pub pidfile: Option<String>,
+
+    /// virtio-net devices, only configurable with the config file
+    #[clap(skip)]
+    pub net: Vec<NetArgs>,
+
+    /// virtio-fs devices, only configurable with the config file
+    #[clap(skip)]
+    pub fs: Vec<FsArgs>,
+}
+
+/// Block device configuration
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DriveArgs {
+    /// Unique id of the drive
+    pub id: String,
+    /// The path of the drive image on the host
+    pub path: String,
+    /// The drive is opened in read-only mode
+    #[serde(default)]
+    pub ro: bool,
+    /// The drive is the root boot device
+    #[serde(default)]
+    pub root: bool,
+}
+
+impl FromStr for DriveArgs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut id = None;
+        let mut path = None;
+        let mut ro = false;
+        let mut root = false;
+
+        for param in s.split(',') {
+            let (key, value) = match param.split_once('=') {
+                Some((key, value)) => (key, value),
+                // a bare flag such as `ro` means `ro=true`
+                None => (param, "true"),
+            };
+            match key {
+                "id" => id = Some(value.to_string()),
+                "path" => path = Some(value.to_string()),
+                "ro" => ro = parse_bool(key, value)?,
+                "root" => root = parse_bool(key, value)?,
+                _ => return Err(format!("unknown drive parameter {key:?}")),
+            }
+        }
+
+        Ok(DriveArgs {
+            id: id.ok_or("the drive id is missing")?,
+            path: path.ok_or("the drive path is missing")?,
+            ro,
+            root,
+        })
+    }
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{key:?} should be true or false, got {value:?}"))
+}
+
+/// Virtio-vsock device configuration
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct VsockArgs {
+    /// The context id of the guest, a free one is picked if it is not given
+    pub cid: Option<u32>,
+    /// The path of the unix socket on the host
+    pub path: String,
+}
+
+impl FromStr for VsockArgs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // keep accepting a bare socket path
+        if !s.contains('=') {
+            return Ok(VsockArgs {
+                cid: None,
+                path: s.to_string(),
+            });
+        }
+
+        let mut cid = None;
+        let mut path = None;
+        for param in s.split(',') {
+            match param.split_once('=') {
+                Some(("cid", value)) => {
+                    cid = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("invalid vsock cid {value:?}"))?,
+                    )
+                }
+                Some(("path", value)) => path = Some(value.to_string()),
+                _ => return Err(format!("unknown vsock parameter {param:?}")),
+            }
+        }
+
+        Ok(VsockArgs {
+            cid,
+            path: path.ok_or("the vsock socket path is missing")?,
+        })
+    }
+}
+
+/// Virtio-net device configuration
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NetArgs {
+    /// Unique id of the network interface
+    pub id: String,
+    /// The name of the tap device on the host
+    pub host_dev_name: String,
+}
+
+/// Virtio-fs device configuration, backed by a vhost-user daemon such as virtiofsd
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FsArgs {
+    /// The tag used to mount the filesystem in the guest
+    pub tag: String,
+    /// The path of the vhost-user socket of the daemon
+    pub sock_path: String,
 }
 
 /// Config boot source including rootfs file path
@@ -216,7 +709,8 @@ pub struct BootArgs {
         short,
         long,
         value_parser,
-        help = "The path of kernel image (Only uncompressed kernel is supported for Dragonball)",
+        env = "DBS_KERNEL_PATH",
+        help = "The path of kernel image, a vmlinux or a gzip, zstd or xz compressed vmlinux or bzImage that is decompressed before boot",
         display_order = 1
     )]
     pub kernel_path: Option<String>,
@@ -225,22 +719,34 @@ pub struct BootArgs {
         short,
         long,
         value_parser,
+        env = "DBS_INITRD_PATH",
         help = "The path of initrd (Optional)",
         display_order = 2
     )]
     pub initrd_path: Option<String>,
 
-    // for kata_rootfs: 'root=/dev/vda1'
+    // `console=` and `root=` are added unless they are given here
     #[clap(
         short,
         long,
         value_parser,
-        default_value = "console=ttyS0 tty0 reboot=k debug panic=1 pci=off root=/dev/vda1",
-        help = "The boot arguments passed to the kernel (Optional)",
+        env = "DBS_BOOT_ARGS",
+        default_value = "reboot=k debug panic=1 pci=off",
+        help = "The base boot arguments passed to the kernel, console= and root= are derived from the serial and root drive if they are absent (Optional)",
         display_order = 3
     )]
     pub boot_args: String,
 
+    #[clap(
+        long,
+        value_parser,
+        env = "DBS_APPEND_BOOT_ARGS",
+        default_value = "",
+        help = "Extra boot arguments appended to the kernel command line (Optional)",
+        display_order = 3
+    )]
+    pub append_boot_args: String,
+
     /// rootfs
     #[clap(flatten)]
     pub rootfs_args: RootfsArgs,
@@ -256,3 +762,75 @@ pub struct UpdateArgs {
     )]
     pub vcpu_resize: Option<usize>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drive_args_from_str() {
+        let drive: DriveArgs = "id=data,path=/tmp/data.img".parse().unwrap();
+        assert_eq!(drive.id, "data");
+        assert_eq!(drive.path, "/tmp/data.img");
+        assert!(!drive.ro);
+        assert!(!drive.root);
+
+        let drive: DriveArgs = "id=root,path=/tmp/root.img,ro=true,root".parse().unwrap();
+        assert!(drive.ro);
+        assert!(drive.root);
+
+        let drive: DriveArgs = "path=/tmp/root.img,id=root,ro,root=false".parse().unwrap();
+        assert_eq!(drive.id, "root");
+        assert!(drive.ro);
+        assert!(!drive.root);
+    }
+
+    #[test]
+    fn test_drive_args_from_str_errors() {
+        let error = |s: &str| s.parse::<DriveArgs>().unwrap_err();
+
+        assert_eq!(error("path=/tmp/data.img"), "the drive id is missing");
+        assert_eq!(error("id=data"), "the drive path is missing");
+        assert_eq!(
+            error("id=data,path=/tmp/data.img,cache=none"),
+            "unknown drive parameter \"cache\""
+        );
+        assert_eq!(
+            error("id=data,path=/tmp/data.img,ro=yes"),
+            "\"ro\" should be true or false, got \"yes\""
+        );
+    }
+
+    #[test]
+    fn test_vsock_args_from_str() {
+        let vsock: VsockArgs = "/tmp/vsock.sock".parse().unwrap();
+        assert_eq!(vsock.cid, None);
+        assert_eq!(vsock.path, "/tmp/vsock.sock");
+
+        let vsock: VsockArgs = "cid=42,path=/tmp/vsock.sock".parse().unwrap();
+        assert_eq!(vsock.cid, Some(42));
+        assert_eq!(vsock.path, "/tmp/vsock.sock");
+
+        let vsock: VsockArgs = "path=/tmp/vsock.sock".parse().unwrap();
+        assert_eq!(vsock.cid, None);
+    }
+
+    #[test]
+    fn test_vsock_args_from_str_errors() {
+        let error = |s: &str| s.parse::<VsockArgs>().unwrap_err();
+
+        assert_eq!(error("cid=42"), "the vsock socket path is missing");
+        assert_eq!(
+            error("cid=-1,path=/tmp/vsock.sock"),
+            "invalid vsock cid \"-1\""
+        );
+        assert_eq!(
+            error("port=1024,path=/tmp/vsock.sock"),
+            "unknown vsock parameter \"port=1024\""
+        );
+        assert_eq!(
+            error("cid,path=/tmp/vsock.sock"),
+            "unknown vsock parameter \"cid\""
+        );
+    }
+}
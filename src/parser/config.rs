@@ -0,0 +1,312 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Command, CommandFactory, FromArgMatches};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+
+use super::DBSArgs;
+use crate::error::Error;
+
+/// Fields holding a clap-flattened struct; their keys are arguments themselves.
+const FLATTENED_ARGS: [&str; 2] = ["cpu_topology", "rootfs_args"];
+
+/// The VM description accepted by `--config`.
+///
+/// `machine` and `boot` use the field names of `CreateArgs` and `BootArgs`,
+/// while the devices are given as lists of tables.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    machine: Map<String, Value>,
+    #[serde(default)]
+    boot: Map<String, Value>,
+    #[serde(default)]
+    drive: Vec<Value>,
+    vsock: Option<Value>,
+    #[serde(default)]
+    net: Vec<Value>,
+    #[serde(default)]
+    fs: Vec<Value>,
+}
+
+/// Parse the command line and merge the config file given by `--config`.
+///
+/// A setting is taken from the file only if it was neither passed on the
+/// command line nor through its `DBS_*` environment variable, so the
+/// precedence is: flags > environment variables > config file > defaults.
+pub fn load_args() -> Result<DBSArgs> {
+    let matches = DBSArgs::command().get_matches();
+    let mut args = DBSArgs::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    if let Some(config_path) = args.config.clone() {
+        let config = read_config_file(Path::new(&config_path))
+            .with_context(|| format!("Failed to load config file {config_path:?}"))
+            .map_err(|e| Error::Config(format!("{e:#}")))?;
+        apply_config_file(&mut args, config, &matches)
+            .with_context(|| format!("Invalid config file {config_path:?}"))
+            .map_err(|e| Error::Config(format!("{e:#}")))?;
+    }
+
+    Ok(args)
+}
+
+fn read_config_file(path: &Path) -> Result<ConfigFile> {
+    let content = std::fs::read_to_string(path)?;
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    let config = match extension {
+        "toml" => toml::from_str(&content)?,
+        "json" => serde_json::from_str(&content)?,
+        "yaml" | "yml" => serde_yaml::from_str(&content)?,
+        _ => {
+            return Err(anyhow!(
+                "unknown config file format {extension:?}, expected toml, json, yaml or yml"
+            ))
+        }
+    };
+
+    Ok(config)
+}
+
+fn apply_config_file(args: &mut DBSArgs, config: ConfigFile, matches: &ArgMatches) -> Result<()> {
+    let mut create = config.machine;
+    for (key, section) in [
+        ("drives", config.drive),
+        ("net", config.net),
+        ("fs", config.fs),
+    ] {
+        if !section.is_empty() {
+            create.insert(key.to_string(), Value::Array(section));
+        }
+    }
+    if let Some(vsock) = config.vsock {
+        create.insert("vsock".to_string(), vsock);
+    }
+
+    let command = DBSArgs::command();
+    args.create_args = merge_section(&args.create_args, create, &command, matches, "machine")?;
+    args.boot_args = merge_section(&args.boot_args, config.boot, &command, matches, "boot")?;
+
+    Ok(())
+}
+
+/// Overlay the values of a config file section onto `current`.
+fn merge_section<T: Serialize + DeserializeOwned>(
+    current: &T,
+    section: Map<String, Value>,
+    command: &Command,
+    matches: &ArgMatches,
+    name: &str,
+) -> Result<T> {
+    let mut value = serde_json::to_value(current)?;
+    overlay(&mut value, section, command, matches, name)?;
+
+    serde_json::from_value(value).with_context(|| format!("invalid value in [{name}]"))
+}
+
+fn overlay(
+    target: &mut Value,
+    section: Map<String, Value>,
+    command: &Command,
+    matches: &ArgMatches,
+    path: &str,
+) -> Result<()> {
+    let target = target
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("[{path}] is not a table"))?;
+
+    for (key, value) in section {
+        let key_path = format!("{path}.{key}");
+        let current = target
+            .get_mut(&key)
+            .ok_or_else(|| anyhow!("unknown key {key_path:?}"))?;
+
+        if FLATTENED_ARGS.contains(&key.as_str()) {
+            match value {
+                Value::Object(nested) => overlay(current, nested, command, matches, &key_path)?,
+                _ => return Err(anyhow!("{key_path:?} should be a table")),
+            }
+        } else if !is_set_explicitly(matches, &key) {
+            *current = possible_value(command, &key, &key_path, value)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Accept the values of an enum argument as the command line does, ignoring
+/// the case and with its aliases, and turn them into the name serde expects.
+fn possible_value(command: &Command, id: &str, key_path: &str, value: Value) -> Result<Value> {
+    let possible_values = match (
+        &value,
+        command.get_arguments().find(|arg| arg.get_id() == id),
+    ) {
+        (Value::String(_), Some(arg)) => arg.get_possible_values(),
+        _ => return Ok(value),
+    };
+    if possible_values.is_empty() {
+        return Ok(value);
+    }
+
+    let given = value.as_str().unwrap_or_default();
+    possible_values
+        .iter()
+        .find(|possible_value| possible_value.matches(given, true))
+        .map(|possible_value| Value::String(possible_value.get_name().to_string()))
+        .ok_or_else(|| {
+            let names: Vec<&str> = possible_values.iter().map(|p| p.get_name()).collect();
+            anyhow!(
+                "invalid value {given:?} for {key_path:?}, expected one of {}",
+                names.join(", ")
+            )
+        })
+}
+
+fn is_set_explicitly(matches: &ArgMatches, id: &str) -> bool {
+    matches.ids().any(|arg| arg.as_str() == id)
+        && matches!(
+            matches.value_source(id),
+            Some(ValueSource::CommandLine) | Some(ValueSource::EnvVariable)
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::parser::args::{CpuPm, MemType, NumaMode, SeccompLevel, VpmuFeature};
+    use crate::test_utils::error_message;
+
+    const CONFIG: &str = r#"
+[machine]
+vcpu = 2
+mem_size = 512
+serial_path = "/tmp/config.sock"
+
+[machine.cpu_topology]
+sockets = 2
+
+[boot]
+kernel_path = "/tmp/config-vmlinux"
+boot_args = "console=ttyS0"
+
+[boot.rootfs_args]
+rootfs = "/tmp/config-rootfs.img"
+
+[[drive]]
+id = "data"
+path = "/tmp/data.img"
+"#;
+
+    /// Taken by every test parsing `DBSArgs`, whose `DBS_*` environment
+    /// variables are shared by the whole test process.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Parse `cli` and merge `config`, the way `load_args` does.
+    fn load(cli: &[&str], config: &str) -> Result<DBSArgs> {
+        load_with_env(cli, &[], config)
+    }
+
+    /// `load` with only the `DBS_*` environment variables of `env` set.
+    fn load_with_env(cli: &[&str], env: &[(&str, &str)], config: &str) -> Result<DBSArgs> {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let saved: Vec<(OsString, OsString)> = std::env::vars_os()
+            .filter(|(key, _)| key.to_string_lossy().starts_with("DBS_"))
+            .collect();
+        for (key, _) in saved.iter() {
+            std::env::remove_var(key);
+        }
+        for (key, value) in env {
+            std::env::set_var(key, value);
+        }
+
+        let args = (|| {
+            let matches = DBSArgs::command()
+                .try_get_matches_from(std::iter::once("dbs-cli").chain(cli.iter().copied()))?;
+            let mut args = DBSArgs::from_arg_matches(&matches)?;
+            apply_config_file(&mut args, toml::from_str(config)?, &matches)?;
+            Ok(args)
+        })();
+
+        for (key, _) in env {
+            std::env::remove_var(key);
+        }
+        for (key, value) in saved {
+            std::env::set_var(key, value);
+        }
+        args
+    }
+
+    #[test]
+    fn test_config_precedence() {
+        let args = load_with_env(
+            &["--vcpu", "4", "--rootfs", "/tmp/cli-rootfs.img"],
+            &[
+                ("DBS_MEM_SIZE", "1024"),
+                ("DBS_KERNEL_PATH", "/tmp/env-vmlinux"),
+            ],
+            CONFIG,
+        )
+        .unwrap();
+
+        // flags > environment variables > config file > defaults
+        assert_eq!(args.create_args.vcpu, 4);
+        assert_eq!(args.create_args.mem_size, 1024);
+        assert_eq!(args.create_args.serial_path, "/tmp/config.sock");
+        assert_eq!(args.create_args.max_vcpu, 1);
+        assert_eq!(
+            args.boot_args.kernel_path.as_deref(),
+            Some("/tmp/env-vmlinux")
+        );
+        assert_eq!(args.boot_args.boot_args, "console=ttyS0");
+        // the keys of the flattened tables are arguments too
+        assert_eq!(args.create_args.cpu_topology.sockets, 2);
+        assert_eq!(
+            args.boot_args.rootfs_args.rootfs.as_deref(),
+            Some("/tmp/cli-rootfs.img")
+        );
+        assert_eq!(args.create_args.drives.len(), 1);
+        assert_eq!(args.create_args.drives[0].id, "data");
+    }
+
+    #[test]
+    fn test_config_errors() {
+        let error = |config: &str| error_message(load(&["--log-level", "info"], config));
+
+        assert_eq!(
+            error("[machine]\nvcpus = 2"),
+            "unknown key \"machine.vcpus\""
+        );
+        assert_eq!(
+            error("[machine]\ncpu_topology = 2"),
+            "\"machine.cpu_topology\" should be a table"
+        );
+        assert!(error("[machine]\nmax_vcpu = \"two\"").starts_with("invalid value in [machine]"));
+        assert!(toml::from_str::<ConfigFile>("[network]\nid = \"eth0\"").is_err());
+        assert_eq!(
+            error("[machine]\nmem_type = \"hugepages\""),
+            "invalid value \"hugepages\" for \"machine.mem_type\", expected one of shmem, hugetlbfs, hugeshmem"
+        );
+    }
+
+    #[test]
+    fn test_config_enum_values() {
+        let config = "[machine]\ncpu_pm = \"OFF\"\nvpmu_feature = \"2\"\nmem_type = \"HugeShmem\"\nnuma_policy = \"Bind\"";
+        let args = load(&["--seccomp-level", "LOG"], config).unwrap();
+
+        // the same spellings as on the command line
+        assert_eq!(args.create_args.cpu_pm, CpuPm::Off);
+        assert_eq!(args.create_args.vpmu_feature, VpmuFeature::Full);
+        assert_eq!(args.create_args.mem_type, MemType::Hugeshmem);
+        assert_eq!(args.create_args.numa_policy, NumaMode::Bind);
+        assert_eq!(args.create_args.seccomp_level, SeccompLevel::Log);
+    }
+}
@@ -5,28 +5,110 @@
 use std::{
     fs::OpenOptions,
     os::unix::io::IntoRawFd,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
     sync::{mpsc::channel, Arc, Mutex},
     thread,
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 
 pub use args::Commands;
 pub use args::DBSArgs;
+pub use config::load_args;
 use dragonball::{api::v1::VmmService, Vmm};
+use slog::info;
 
+use crate::affinity::CpuAffinity;
 use crate::api_server::ApiServer;
+use crate::cgroup::{Cgroup, CgroupGuard};
 use crate::cli_instance::CliInstance;
+use crate::daemon::{Daemon, PidFile};
+use crate::ephemeral::prepare_ephemeral;
+use crate::exit_code;
+use crate::image_lock::lock_drives;
+use crate::instance_dir::InstanceDir;
+use crate::jailer::enter_jail;
+use crate::kernel_image::prepare_kernel;
+use crate::numa::NumaPolicy;
+use crate::seccomp::build_filters;
+use crate::signal::{block_stop_signals, handle_stop_signals};
+use crate::validation::{check_host, validate_args, validate_spec};
+use crate::vm_spec::VmSpec;
+use crate::vsock::acquire_cid;
 
 pub mod args;
+pub mod config;
 
 const KVM_DEVICE: &str = "/dev/kvm";
+/// Directory for the host-wide state shared by dbs-cli instances
+pub const RUN_DIR: &str = "/run/dbs-cli";
+
+/// Create and run the VM, and return the exit code of dbs-cli once it stops.
+pub fn run_with_cli(mut args: DBSArgs, daemon: Option<Daemon>) -> Result<i32> {
+    let kernel_cmdline = validate_args(&args)?;
+    // a stop signal received from now on still removes what is set up below
+    block_stop_signals()?;
+
+    let _pidfile = match &args.create_args.pidfile {
+        Some(path) => Some(PidFile::create(path)?),
+        None => None,
+    };
+
+    // files generated for this VM, removed when it exits
+    let instance_dir = InstanceDir::new()?;
+    if let Some(kernel_path) = args.boot_args.kernel_path.as_mut() {
+        *kernel_path = prepare_kernel(kernel_path.as_ref(), instance_dir.path())?
+            .to_string_lossy()
+            .into_owned();
+    }
+    // keep the drive images locked until the VM exits
+    let _drive_locks = lock_drives(&args)?;
+    // the private rootfs clone and scratch drive, removed when the VM exits
+    let ephemeral_dir = prepare_ephemeral(&mut args)?;
 
-pub fn run_with_cli(args: DBSArgs) -> Result<i32> {
     let mut cli_instance = CliInstance::new("dbs-cli");
+    let seccomp_filters = build_filters(&args)?;
+    cli_instance.vmm_seccomp = seccomp_filters.vmm;
+    cli_instance.vcpu_seccomp = seccomp_filters.vcpu;
+
+    // reserve the vsock guest cid for the whole life of the VM
+    let _cid_lease = match args.create_args.vsock.as_mut() {
+        Some(vsock) => {
+            let lease = acquire_cid(vsock.cid)?;
+            vsock.cid = Some(lease.cid);
+            Some(lease)
+        }
+        None => None,
+    };
 
     let kvm = OpenOptions::new().read(true).write(true).open(KVM_DEVICE)?;
 
+    // the jailed VMM is forked in the cgroup
+    let cgroup = Cgroup::setup(&args)?.map(Arc::new);
+    let _cgroup_guard = cgroup.clone().map(CgroupGuard);
+    cli_instance.cgroup = cgroup.clone();
+
+    // the threads of the VMM, and the jailed VMM, inherit the CPUs of this one
+    let affinity = CpuAffinity::from_args(&args)?;
+    affinity.pin_vmm()?;
+    cli_instance.affinity = affinity.clone();
+    cli_instance.numa_policy = NumaPolicy::from_args(&args)?;
+
+    // the parent of a jailed VMM only waits for it
+    let generated: Vec<&Path> = std::iter::once(instance_dir.path())
+        .chain(ephemeral_dir.as_ref().map(|dir| dir.path()))
+        .collect();
+    if let Some(exit_code) = enter_jail(&mut args, daemon.as_ref(), &generated)? {
+        return Ok(exit_code);
+    }
+
+    // the errors of the VM creation and of the api server, the exit code of
+    // the VMM event loop, and the stop signals, the first one received stops
+    // dbs-cli
+    let (to_main, from_threads) = channel::<Result<i32>>();
+    handle_stop_signals(to_main.clone())?;
+
     let (to_vmm, from_runtime) = channel();
     let (to_runtime, from_vmm) = channel();
 
@@ -38,51 +120,104 @@ pub fn run_with_cli(args: DBSArgs) -> Result<i32> {
     let api_event_fd2 = cli_instance
         .to_vmm_fd
         .try_clone()
-        .expect("Failed to dup eventfd");
+        .context("Failed to dup eventfd")?;
     let vmm = Vmm::new(
         cli_instance.vmm_shared_info.clone(),
         api_event_fd2,
-        cli_instance.seccomp.clone(),
-        cli_instance.seccomp.clone(),
+        cli_instance.vmm_seccomp.clone(),
+        cli_instance.vcpu_seccomp.clone(),
         Some(kvm.into_raw_fd()),
     )
-    .expect("Failed to start vmm");
+    .map_err(|e| anyhow!("Failed to start vmm: {e:?}"))?;
 
     let api_event_fd3 = cli_instance
         .to_vmm_fd
         .try_clone()
-        .expect("Failed to dup eventfd");
-
+        .context("Failed to dup eventfd")?;
+
+    // bound before the VM is started, so a socket that cannot be created
+    // stops `create`, and the daemon does not report a VM without api
+    let api_listener = if args.api_sock_path.is_empty() {
+        None
+    } else {
+        Some(ApiServer::bind(&args.api_sock_path)?)
+    };
     let mut api_server = ApiServer::new(
         cli_instance.to_vmm.clone(),
         cli_instance.from_vmm.clone(),
         api_event_fd3,
+        cli_instance.vmm_shared_info.clone(),
+        cli_instance.devices.clone(),
+        cgroup,
+        affinity,
     );
+    api_server.mem_file_path = args.create_args.mem_file_path.clone();
 
-    // clone the arguments for other thread to use
-    let clone_args = args.clone();
+    // run the VMM event loop in its own thread, so the main thread can stop
+    // dbs-cli when the VM fails to start
+    let vmm_exit = to_main.clone();
     thread::Builder::new()
-        .name("set_cfg".to_owned())
+        .name("vmm_master".to_owned())
         .spawn(move || {
-            cli_instance
-                .run_vmm_server(clone_args)
-                .expect("Failed to run server.");
+            let vmm_exit_code = Vmm::run_vmm_event_loop(Arc::new(Mutex::new(vmm)), vmm_service);
+            info!(slog_scope::logger(), "the VMM event loop stopped";
+                "vmm_exit_code" => vmm_exit_code);
+            let _ = vmm_exit.send(Ok(exit_code::from_vmm(vmm_exit_code)));
         })
-        .unwrap();
+        .context("Failed to spawn the vmm_master thread")?;
 
-    if !args.api_sock_path.is_empty() {
+    if let Some(api_listener) = api_listener {
+        let api_exit = to_main.clone();
         thread::Builder::new()
             .name("api_server".to_owned())
             .spawn(move || {
-                api_server
-                    .run_api_server(&args.api_sock_path)
-                    .expect("Failed to api server.");
+                if let Err(e) = api_server.run_api_server(api_listener) {
+                    let _ = api_exit.send(Err(e.context("The api server stopped")));
+                }
             })
-            .unwrap();
+            .context("Failed to spawn the api_server thread")?;
+    }
+
+    // clone the arguments for other thread to use
+    let clone_args = args.clone();
+    thread::Builder::new()
+        .name("set_cfg".to_owned())
+        .spawn(move || {
+            // a panic while creating the VM is a failure to report as well
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                cli_instance.run_vmm_server(clone_args, kernel_cmdline)
+            }))
+            .unwrap_or_else(|_| Err(anyhow!("the VM creation panicked")))
+            .and_then(|()| daemon.map_or(Ok(()), Daemon::ready));
+            if let Err(e) = result {
+                let _ = to_main.send(Err(e.context("Failed to create the VM")));
+            }
+        })
+        .context("Failed to spawn the set_cfg thread")?;
+
+    match from_threads.recv() {
+        Ok(Ok(exit_code)) => Ok(exit_code),
+        // returning drops the guards of the VM, then the VMM stops with dbs-cli
+        Ok(Err(e)) => Err(e),
+        // the VMM thread panicked once the VM was created
+        Err(_) => {
+            eprintln!("dbs-cli: the VMM thread stopped unexpectedly");
+            Ok(exit_code::VMM_ERROR)
+        }
+    }
+}
+
+/// Validate the arguments and print the VM specification that `create` would
+/// send to the VMM, without creating the VM. The problems of this host are
+/// only warnings, the VM may be created on another one.
+pub fn print_dry_run(args: DBSArgs) -> Result<()> {
+    let kernel_cmdline = validate_spec(&args)?;
+
+    let spec = VmSpec::build(&args, kernel_cmdline)?;
+    for warning in check_host(&args).iter().chain(spec.warnings.iter()) {
+        eprintln!("dbs-cli: warning: {warning}");
     }
+    println!("{}", serde_json::to_string_pretty(&spec)?);
 
-    Ok(Vmm::run_vmm_event_loop(
-        Arc::new(Mutex::new(vmm)),
-        vmm_service,
-    ))
+    Ok(())
 }
@@ -0,0 +1,333 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::Result;
+
+const SECTOR_SIZE: u64 = 512;
+
+/// Offset and value of the ext2/3/4 superblock magic.
+const EXT4_MAGIC_OFFSET: u64 = 1024 + 56;
+const EXT4_MAGIC: [u8; 2] = [0x53, 0xef];
+/// Offset and value of the squashfs superblock magic ("hsqs").
+const SQUASHFS_MAGIC_OFFSET: u64 = 0;
+const SQUASHFS_MAGIC: [u8; 4] = *b"hsqs";
+/// Offset and value of the erofs superblock magic.
+const EROFS_MAGIC_OFFSET: u64 = 1024;
+const EROFS_MAGIC: [u8; 4] = [0xe2, 0xe1, 0xf5, 0xe0];
+
+/// The GPT header is in the second sector.
+const GPT_SIGNATURE: [u8; 8] = *b"EFI PART";
+/// Do not trust GPT headers claiming more entries than a disk normally has.
+const GPT_MAX_ENTRIES: u32 = 128;
+/// The size of a GPT partition entry is 128 bytes or more, within a sector.
+const GPT_MIN_ENTRY_SIZE: u64 = 128;
+/// The MBR partition table and its boot signature.
+const MBR_TABLE_OFFSET: usize = 446;
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+/// Extended partitions only contain other partitions.
+const MBR_EXTENDED_TYPES: [u8; 3] = [0x05, 0x0f, 0x85];
+
+/// Filesystems recognized in a rootfs image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsType {
+    Ext4,
+    Squashfs,
+    Erofs,
+}
+
+impl FsType {
+    /// The name used by `rootfstype=`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FsType::Ext4 => "ext4",
+            FsType::Squashfs => "squashfs",
+            FsType::Erofs => "erofs",
+        }
+    }
+}
+
+/// Where the root filesystem lives in a rootfs image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RootfsLayout {
+    /// The partition number, `None` for an image that is a bare filesystem
+    pub partition: Option<u32>,
+    pub fs_type: FsType,
+}
+
+/// Detect the layout of a rootfs image: a bare filesystem, or the first
+/// partition of a GPT or MBR partitioned disk holding a known filesystem.
+///
+/// Returns `None` if no known filesystem is found.
+pub fn detect_layout<P: AsRef<Path>>(path: P) -> Result<Option<RootfsLayout>> {
+    let mut image = File::open(path)?;
+
+    if let Some(partition) = detect_gpt(&mut image)? {
+        return Ok(Some(partition));
+    }
+    if let Some(fs_type) = detect_fs(&mut image, 0)? {
+        return Ok(Some(RootfsLayout {
+            partition: None,
+            fs_type,
+        }));
+    }
+    detect_mbr(&mut image)
+}
+
+fn detect_gpt(image: &mut File) -> Result<Option<RootfsLayout>> {
+    let header = read_at(image, SECTOR_SIZE, 92)?;
+    if header.len() < 92 || header[0..8] != GPT_SIGNATURE {
+        return Ok(None);
+    }
+
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let entries = u32::from_le_bytes(header[80..84].try_into().unwrap()).min(GPT_MAX_ENTRIES);
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap()) as u64;
+    if !(GPT_MIN_ENTRY_SIZE..=SECTOR_SIZE).contains(&entry_size) {
+        return Ok(None);
+    }
+    // the offsets of a corrupt table may overflow
+    let entries_offset = match entries_lba.checked_mul(SECTOR_SIZE) {
+        Some(offset) => offset,
+        None => return Ok(None),
+    };
+
+    for index in 0..entries {
+        let entry_offset = match entries_offset.checked_add(index as u64 * entry_size) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let entry = read_at(image, entry_offset, 48)?;
+        if entry.len() < 48 {
+            break;
+        }
+        // an unused entry has a null partition type
+        if entry[0..16].iter().all(|byte| *byte == 0) {
+            continue;
+        }
+        let first_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+        if let Some(fs_type) = detect_partition_fs(image, first_lba)? {
+            return Ok(Some(RootfsLayout {
+                partition: Some(index + 1),
+                fs_type,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+fn detect_mbr(image: &mut File) -> Result<Option<RootfsLayout>> {
+    let mbr = read_at(image, 0, SECTOR_SIZE as usize)?;
+    if mbr.len() < SECTOR_SIZE as usize || mbr[510..512] != MBR_SIGNATURE {
+        return Ok(None);
+    }
+
+    for index in 0..4 {
+        let entry = &mbr[MBR_TABLE_OFFSET + index * 16..MBR_TABLE_OFFSET + (index + 1) * 16];
+        let partition_type = entry[4];
+        if partition_type == 0 || MBR_EXTENDED_TYPES.contains(&partition_type) {
+            continue;
+        }
+        let first_lba = u32::from_le_bytes(entry[8..12].try_into().unwrap()) as u64;
+        if let Some(fs_type) = detect_partition_fs(image, first_lba)? {
+            return Ok(Some(RootfsLayout {
+                partition: Some(index as u32 + 1),
+                fs_type,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Look for a known filesystem in the partition starting at `first_lba`,
+/// which is none if its offset overflows.
+fn detect_partition_fs(image: &mut File, first_lba: u64) -> Result<Option<FsType>> {
+    match first_lba.checked_mul(SECTOR_SIZE) {
+        Some(offset) => detect_fs(image, offset),
+        None => Ok(None),
+    }
+}
+
+/// Look for a known filesystem superblock at `offset`.
+fn detect_fs(image: &mut File, offset: u64) -> Result<Option<FsType>> {
+    for (fs_type, magic_offset, magic) in [
+        (FsType::Squashfs, SQUASHFS_MAGIC_OFFSET, &SQUASHFS_MAGIC[..]),
+        (FsType::Erofs, EROFS_MAGIC_OFFSET, &EROFS_MAGIC[..]),
+        (FsType::Ext4, EXT4_MAGIC_OFFSET, &EXT4_MAGIC[..]),
+    ] {
+        let magic_offset = match offset.checked_add(magic_offset) {
+            Some(magic_offset) => magic_offset,
+            None => continue,
+        };
+        if read_at(image, magic_offset, magic.len())? == magic {
+            return Ok(Some(fs_type));
+        }
+    }
+    Ok(None)
+}
+
+/// Read up to `len` bytes at `offset`, less if the image is shorter.
+fn read_at(image: &mut File, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::with_capacity(len);
+    // past the end of any file, but lseek would fail on it
+    if offset > i64::MAX as u64 {
+        return Ok(buf);
+    }
+    image.seek(SeekFrom::Start(offset))?;
+    image.take(len as u64).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::ImageDir;
+
+    const MIB: u64 = 1 << 20;
+
+    fn layout(partition: Option<u32>, fs_type: FsType) -> Option<RootfsLayout> {
+        Some(RootfsLayout { partition, fs_type })
+    }
+
+    #[test]
+    fn test_detect_bare_filesystems() {
+        let dir = ImageDir::new();
+
+        let ext4 = dir.sparse_image("ext4.img", MIB, &[(EXT4_MAGIC_OFFSET, &EXT4_MAGIC)]);
+        assert_eq!(detect_layout(ext4).unwrap(), layout(None, FsType::Ext4));
+
+        let squashfs = dir.sparse_image("squashfs.img", MIB, &[(0, &SQUASHFS_MAGIC)]);
+        assert_eq!(
+            detect_layout(squashfs).unwrap(),
+            layout(None, FsType::Squashfs)
+        );
+
+        let erofs = dir.sparse_image("erofs.img", MIB, &[(EROFS_MAGIC_OFFSET, &EROFS_MAGIC)]);
+        assert_eq!(detect_layout(erofs).unwrap(), layout(None, FsType::Erofs));
+
+        let unknown = dir.sparse_image("unknown.img", MIB, &[]);
+        assert_eq!(detect_layout(unknown).unwrap(), None);
+
+        // shorter than any superblock
+        let short = dir.sparse_image("short.img", 16, &[]);
+        assert_eq!(detect_layout(short).unwrap(), None);
+    }
+
+    #[test]
+    fn test_detect_mbr() {
+        let dir = ImageDir::new();
+        let partition_start: u32 = 2048;
+
+        // an extended partition, then a Linux partition holding ext4
+        let mut extended = [0u8; 16];
+        extended[4] = 0x05;
+        extended[8..12].copy_from_slice(&1u32.to_le_bytes());
+        let mut linux = [0u8; 16];
+        linux[4] = 0x83;
+        linux[8..12].copy_from_slice(&partition_start.to_le_bytes());
+
+        let partition_offset = partition_start as u64 * SECTOR_SIZE;
+        let mbr = dir.sparse_image(
+            "mbr.img",
+            2 * MIB,
+            &[
+                (MBR_TABLE_OFFSET as u64, &extended),
+                (MBR_TABLE_OFFSET as u64 + 16, &linux),
+                (510, &MBR_SIGNATURE),
+                (partition_offset + EXT4_MAGIC_OFFSET, &EXT4_MAGIC),
+            ],
+        );
+        assert_eq!(detect_layout(mbr).unwrap(), layout(Some(2), FsType::Ext4));
+
+        // the same table without the boot signature is not a MBR
+        let no_signature = dir.sparse_image(
+            "no-signature.img",
+            2 * MIB,
+            &[
+                (MBR_TABLE_OFFSET as u64 + 16, &linux),
+                (partition_offset + EXT4_MAGIC_OFFSET, &EXT4_MAGIC),
+            ],
+        );
+        assert_eq!(detect_layout(no_signature).unwrap(), None);
+    }
+
+    #[test]
+    fn test_detect_gpt() {
+        let dir = ImageDir::new();
+        let entries_lba: u64 = 2;
+        let entry_size: u64 = 128;
+        let partition_start: u64 = 2048;
+
+        let mut header = [0u8; 92];
+        header[0..8].copy_from_slice(&GPT_SIGNATURE);
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&(entry_size as u32).to_le_bytes());
+        // the first entry is unused, the second one holds erofs
+        let mut entry = [0u8; 48];
+        entry[0..16].copy_from_slice(&[0xaf; 16]);
+        entry[32..40].copy_from_slice(&partition_start.to_le_bytes());
+
+        let partition_offset = partition_start * SECTOR_SIZE;
+        let gpt = dir.sparse_image(
+            "gpt.img",
+            2 * MIB,
+            &[
+                // the protective MBR
+                (510, &MBR_SIGNATURE),
+                (SECTOR_SIZE, &header),
+                (entries_lba * SECTOR_SIZE + entry_size, &entry),
+                (partition_offset + EROFS_MAGIC_OFFSET, &EROFS_MAGIC),
+            ],
+        );
+        assert_eq!(detect_layout(gpt).unwrap(), layout(Some(2), FsType::Erofs));
+
+        // a partition without a known filesystem
+        let unknown = dir.sparse_image(
+            "gpt-unknown.img",
+            2 * MIB,
+            &[
+                (SECTOR_SIZE, &header),
+                (entries_lba * SECTOR_SIZE + entry_size, &entry),
+            ],
+        );
+        assert_eq!(detect_layout(unknown).unwrap(), None);
+    }
+
+    #[test]
+    fn test_detect_corrupt_gpt() {
+        let dir = ImageDir::new();
+        let gpt = |name: &str, entries_lba: u64, entry_size: u32, first_lba: u64| {
+            let mut header = [0u8; 92];
+            header[0..8].copy_from_slice(&GPT_SIGNATURE);
+            header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+            header[80..84].copy_from_slice(&1u32.to_le_bytes());
+            header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+            let mut entry = [0u8; 48];
+            entry[0..16].copy_from_slice(&[0xaf; 16]);
+            entry[32..40].copy_from_slice(&first_lba.to_le_bytes());
+            let path = dir.sparse_image(
+                name,
+                MIB,
+                &[(SECTOR_SIZE, &header), (2 * SECTOR_SIZE, &entry)],
+            );
+            detect_layout(path).unwrap()
+        };
+
+        // offsets overflowing a u64 are not followed
+        assert_eq!(gpt("entries-lba.img", u64::MAX, 128, 4), None);
+        assert_eq!(gpt("first-lba.img", 2, 128, u64::MAX / 256), None);
+        assert_eq!(
+            gpt("magic-offset.img", 2, 128, u64::MAX / SECTOR_SIZE),
+            None
+        );
+        // entries smaller than the GPT minimum or larger than a sector
+        assert_eq!(gpt("small-entry.img", 2, 0, 4), None);
+        assert_eq!(gpt("large-entry.img", 2, 1 << 31, 4), None);
+    }
+}
@@ -0,0 +1,428 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use anyhow::{anyhow, Context, Result};
+use seccompiler::{
+    compile_from_json, BpfMap, BpfProgram, SeccompAction, SeccompFilter, SeccompRule, TargetArch,
+};
+use serde_json::Value;
+
+use crate::parser::args::SeccompLevel;
+use crate::parser::DBSArgs;
+
+/// The keys of the filters in a `--seccomp-policy` file.
+const VMM_FILTER: &str = "vmm";
+const VCPU_FILTER: &str = "vcpu";
+
+/// Syscalls of the VMM thread: the event loop, device emulation and block I/O.
+const VMM_SYSCALLS: &[i64] = &[
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_brk,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_close,
+    libc::SYS_connect,
+    libc::SYS_dup,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_fallocate,
+    libc::SYS_fcntl,
+    libc::SYS_fdatasync,
+    libc::SYS_fstat,
+    libc::SYS_fstatfs,
+    libc::SYS_fsync,
+    libc::SYS_ftruncate,
+    libc::SYS_futex,
+    libc::SYS_getpid,
+    libc::SYS_getrandom,
+    libc::SYS_getsockname,
+    libc::SYS_getsockopt,
+    libc::SYS_gettid,
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+    libc::SYS_io_uring_setup,
+    libc::SYS_ioctl,
+    libc::SYS_listen,
+    libc::SYS_lseek,
+    libc::SYS_madvise,
+    libc::SYS_memfd_create,
+    libc::SYS_mmap,
+    libc::SYS_mprotect,
+    libc::SYS_mremap,
+    libc::SYS_munmap,
+    libc::SYS_nanosleep,
+    libc::SYS_newfstatat,
+    libc::SYS_openat,
+    libc::SYS_pipe2,
+    libc::SYS_ppoll,
+    libc::SYS_prctl,
+    libc::SYS_pread64,
+    libc::SYS_preadv,
+    libc::SYS_pwrite64,
+    libc::SYS_pwritev,
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_restart_syscall,
+    libc::SYS_rseq,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_sched_yield,
+    libc::SYS_sendmsg,
+    libc::SYS_sendto,
+    libc::SYS_set_robust_list,
+    libc::SYS_setsockopt,
+    libc::SYS_shutdown,
+    libc::SYS_sigaltstack,
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_statfs,
+    libc::SYS_statx,
+    libc::SYS_tgkill,
+    libc::SYS_timerfd_create,
+    libc::SYS_timerfd_settime,
+    libc::SYS_unlinkat,
+    libc::SYS_write,
+    libc::SYS_writev,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+];
+
+/// Syscalls of the vCPU threads: KVM_RUN and the emulation of MMIO and PIO exits.
+const VCPU_SYSCALLS: &[i64] = &[
+    libc::SYS_brk,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_close,
+    libc::SYS_epoll_ctl,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_fcntl,
+    libc::SYS_fstat,
+    libc::SYS_futex,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_ioctl,
+    libc::SYS_lseek,
+    libc::SYS_madvise,
+    libc::SYS_mmap,
+    libc::SYS_mprotect,
+    libc::SYS_munmap,
+    libc::SYS_nanosleep,
+    libc::SYS_newfstatat,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_read,
+    libc::SYS_restart_syscall,
+    libc::SYS_rseq,
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sched_yield,
+    libc::SYS_sendmsg,
+    libc::SYS_set_robust_list,
+    libc::SYS_sigaltstack,
+    libc::SYS_statx,
+    libc::SYS_tgkill,
+    libc::SYS_timerfd_settime,
+    libc::SYS_write,
+    libc::SYS_writev,
+];
+
+/// The seccomp filters of the VMM and vCPU threads.
+pub struct SeccompFilters {
+    pub vmm: BpfProgram,
+    pub vcpu: BpfProgram,
+}
+
+/// Build the seccomp filters chosen by `--seccomp-level` and `--seccomp-policy`.
+///
+/// The filters are empty, i.e. nothing is filtered, with the `none` level.
+/// Otherwise a syscall missing from the allowlist is logged with the `log`
+/// level, or kills dbs-cli with the `kill` level. The allowlists are the
+/// built-in ones, or the `vmm` and `vcpu` filters of the policy file.
+pub fn build_filters(args: &DBSArgs) -> Result<SeccompFilters> {
+    // the action, and its name in a policy file
+    let (mismatch_action, mismatch_action_name) = match args.create_args.seccomp_level {
+        SeccompLevel::None => {
+            return Ok(SeccompFilters {
+                vmm: vec![],
+                vcpu: vec![],
+            })
+        }
+        SeccompLevel::Log => (SeccompAction::Log, "log"),
+        SeccompLevel::Kill => (SeccompAction::KillProcess, "kill_process"),
+    };
+    let arch: TargetArch = std::env::consts::ARCH
+        .try_into()
+        .map_err(|e| anyhow!("seccomp is not supported on this architecture: {e:?}"))?;
+
+    let mut filters = SeccompFilters {
+        vmm: allowlist(VMM_SYSCALLS, mismatch_action.clone(), arch)?,
+        vcpu: allowlist(VCPU_SYSCALLS, mismatch_action, arch)?,
+    };
+
+    if let Some(policy_path) = &args.create_args.seccomp_policy {
+        let mut policy = load_policy(policy_path, mismatch_action_name, arch)
+            .with_context(|| format!("Invalid seccomp policy {policy_path:?}"))?;
+        if let Some(vmm) = policy.remove(VMM_FILTER) {
+            filters.vmm = vmm;
+        }
+        if let Some(vcpu) = policy.remove(VCPU_FILTER) {
+            filters.vcpu = vcpu;
+        }
+    }
+
+    Ok(filters)
+}
+
+fn allowlist(
+    syscalls: &[i64],
+    mismatch_action: SeccompAction,
+    arch: TargetArch,
+) -> Result<BpfProgram> {
+    let rules: BTreeMap<i64, Vec<SeccompRule>> =
+        syscalls.iter().map(|syscall| (*syscall, vec![])).collect();
+    let filter = SeccompFilter::new(rules, mismatch_action, SeccompAction::Allow, arch)
+        .map_err(|e| anyhow!("Failed to build the seccomp filter: {e}"))?;
+
+    filter
+        .try_into()
+        .map_err(|e| anyhow!("Failed to compile the seccomp filter: {e}"))
+}
+
+/// Compile the filters of a seccompiler JSON policy, whose mismatch action is
+/// replaced by the one of `--seccomp-level`.
+fn load_policy(path: &str, mismatch_action: &str, arch: TargetArch) -> Result<BpfMap> {
+    let mut policy: Value = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    let filters = policy
+        .as_object_mut()
+        .ok_or_else(|| anyhow!("the policy should be a JSON object of filters"))?;
+    if let Some(key) = filters
+        .keys()
+        .find(|key| *key != VMM_FILTER && *key != VCPU_FILTER)
+    {
+        return Err(anyhow!(
+            "unknown filter {key:?}, expected \"{VMM_FILTER}\" or \"{VCPU_FILTER}\""
+        ));
+    }
+    for filter in filters.values_mut() {
+        if let Some(filter) = filter.as_object_mut() {
+            filter.insert(
+                String::from("mismatch_action"),
+                Value::from(mismatch_action),
+            );
+        }
+    }
+
+    compile_from_json(policy.to_string().as_bytes(), arch).map_err(|e| anyhow!("{e}"))
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use std::ffi::CString;
+    use std::fs::{File, OpenOptions};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::thread;
+
+    use nix::sys::signal::Signal;
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+
+    use super::*;
+
+    const KVM_CREATE_VM: libc::c_ulong = 0xae01;
+    const KVM_GET_VCPU_MMAP_SIZE: libc::c_ulong = 0xae04;
+    const KVM_CREATE_VCPU: libc::c_ulong = 0xae41;
+    const KVM_SET_USER_MEMORY_REGION: libc::c_ulong = 0x4020_ae46;
+    const KVM_RUN: libc::c_ulong = 0xae80;
+    const KVM_SET_REGS: libc::c_ulong = 0x4090_ae82;
+    const KVM_GET_SREGS: libc::c_ulong = 0x8138_ae83;
+    const KVM_SET_SREGS: libc::c_ulong = 0x4138_ae84;
+    const KVM_EXIT_IO: u32 = 2;
+    const KVM_EXIT_HLT: u32 = 5;
+
+    /// `mov dx, 0x3f8; mov al, 'A'; out dx, al; hlt` in real mode: the guest
+    /// writes to the serial port, then halts.
+    const GUEST_CODE: [u8; 7] = [0xba, 0xf8, 0x03, 0xb0, 0x41, 0xee, 0xf4];
+    const GUEST_CODE_ADDR: usize = 0x1000;
+    const GUEST_MEM_SIZE: usize = 0x10000;
+    /// Sizes of `struct kvm_regs` and `struct kvm_sregs`
+    const REGS_SIZE: usize = 0x90;
+    const SREGS_SIZE: usize = 0x138;
+
+    #[repr(C)]
+    struct UserMemoryRegion {
+        slot: u32,
+        flags: u32,
+        guest_phys_addr: u64,
+        memory_size: u64,
+        userspace_addr: u64,
+    }
+
+    fn ioctl<T>(fd: &impl AsRawFd, request: libc::c_ulong, arg: T) -> i32 {
+        // SAFETY: the arguments of the KVM ioctls are checked by the callers.
+        let ret = unsafe { libc::ioctl(fd.as_raw_fd(), request as _, arg) };
+        assert!(ret >= 0, "{}", std::io::Error::last_os_error());
+        ret
+    }
+
+    /// Run a minimal guest the way the VMM does, under the built-in
+    /// allowlists with the `kill` action. The VMM thread creates the guest
+    /// memory from a memfd and the vcpu, then the vcpu thread runs the guest
+    /// until it halts, emulating its serial output.
+    ///
+    /// The guest runs in a forked child, so a syscall missing from the
+    /// allowlists only kills the child, with SIGSYS, and not the other tests.
+    #[test]
+    fn test_builtin_allowlists_run_a_guest() {
+        let kvm = match OpenOptions::new().read(true).write(true).open("/dev/kvm") {
+            Ok(kvm) => kvm,
+            // KVM is not available to run the test
+            Err(_) => return,
+        };
+
+        // SAFETY: the child only runs the guest, then exits without unwinding.
+        match unsafe { fork() }.unwrap() {
+            ForkResult::Child => {
+                let output = panic::catch_unwind(AssertUnwindSafe(|| run_guest(kvm)));
+                let code = if matches!(output.as_deref(), Ok(b"A")) {
+                    0
+                } else {
+                    1
+                };
+                // SAFETY: exit the child without running the test harness.
+                unsafe { libc::_exit(code) }
+            }
+            ForkResult::Parent { child } => match waitpid(child, None).unwrap() {
+                WaitStatus::Exited(_, 0) => {}
+                WaitStatus::Signaled(_, Signal::SIGSYS, _) => panic!(
+                    "a syscall missing from the allowlists killed the guest, see type=1326 in dmesg"
+                ),
+                status => panic!("the guest did not write to its serial port: {status:?}"),
+            },
+        }
+    }
+
+    /// Returns the serial output of the guest.
+    fn run_guest(kvm: File) -> Vec<u8> {
+        let arch: TargetArch = std::env::consts::ARCH.try_into().unwrap();
+        let vmm_filter = allowlist(VMM_SYSCALLS, SeccompAction::KillProcess, arch).unwrap();
+        let vcpu_filter = allowlist(VCPU_SYSCALLS, SeccompAction::KillProcess, arch).unwrap();
+
+        thread::spawn(move || {
+            seccompiler::apply_filter(&vmm_filter).unwrap();
+
+            // SAFETY: the fds are created by the ioctl and memfd_create.
+            let vm = unsafe { File::from_raw_fd(ioctl(&kvm, KVM_CREATE_VM, 0)) };
+            let name = CString::new("guest").unwrap();
+            let memfd =
+                unsafe { File::from_raw_fd(libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC)) };
+            memfd.set_len(GUEST_MEM_SIZE as u64).unwrap();
+            // SAFETY: a new shared mapping of the whole memfd.
+            let memory = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    GUEST_MEM_SIZE,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    memfd.as_raw_fd(),
+                    0,
+                )
+            };
+            assert_ne!(memory, libc::MAP_FAILED);
+            // SAFETY: the code fits in the mapping.
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    GUEST_CODE.as_ptr(),
+                    memory.cast::<u8>().add(GUEST_CODE_ADDR),
+                    GUEST_CODE.len(),
+                )
+            };
+            let region = UserMemoryRegion {
+                slot: 0,
+                flags: 0,
+                guest_phys_addr: 0,
+                memory_size: GUEST_MEM_SIZE as u64,
+                userspace_addr: memory as u64,
+            };
+            ioctl(&vm, KVM_SET_USER_MEMORY_REGION, &region as *const _);
+            // SAFETY: the fd is created by the ioctl.
+            let vcpu = unsafe { File::from_raw_fd(ioctl(&vm, KVM_CREATE_VCPU, 0)) };
+            let run_size = ioctl(&kvm, KVM_GET_VCPU_MMAP_SIZE, 0) as usize;
+
+            thread::spawn(move || {
+                seccompiler::apply_filter(&vcpu_filter).unwrap();
+
+                // SAFETY: the kvm_run area of the vcpu, of the size given by KVM.
+                let run = unsafe {
+                    libc::mmap(
+                        std::ptr::null_mut(),
+                        run_size,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                        libc::MAP_SHARED,
+                        vcpu.as_raw_fd(),
+                        0,
+                    )
+                };
+                assert_ne!(run, libc::MAP_FAILED);
+                let run = run.cast::<u8>();
+
+                // real mode, starting at the guest code
+                let mut sregs = [0u8; SREGS_SIZE];
+                ioctl(&vcpu, KVM_GET_SREGS, sregs.as_mut_ptr());
+                // kvm_sregs.cs: u64 base, u32 limit, u16 selector
+                sregs[0..8].copy_from_slice(&0u64.to_ne_bytes());
+                sregs[12..14].copy_from_slice(&0u16.to_ne_bytes());
+                ioctl(&vcpu, KVM_SET_SREGS, sregs.as_ptr());
+                let mut regs = [0u64; REGS_SIZE / 8];
+                // rip and rflags, whose bit 1 is always set
+                regs[16] = GUEST_CODE_ADDR as u64;
+                regs[17] = 0x2;
+                ioctl(&vcpu, KVM_SET_REGS, regs.as_ptr());
+
+                let mut output = vec![];
+                loop {
+                    ioctl(&vcpu, KVM_RUN, 0);
+                    // SAFETY: the fields of kvm_run are read within its mapping:
+                    // exit_reason at 8, and for an I/O exit, its data offset at 40.
+                    unsafe {
+                        match *run.add(8).cast::<u32>() {
+                            KVM_EXIT_IO => {
+                                let data_offset = *run.add(40).cast::<u64>() as usize;
+                                output.push(*run.add(data_offset));
+                            }
+                            KVM_EXIT_HLT => break,
+                            reason => panic!("unexpected exit reason {reason}"),
+                        }
+                    }
+                }
+                output
+            })
+            .join()
+            .unwrap()
+        })
+        .join()
+        .unwrap()
+    }
+}
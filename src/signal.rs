@@ -0,0 +1,90 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use anyhow::{Context, Result};
+use nix::sys::signal::{kill, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+
+use crate::exit_code;
+
+/// The signals that stop the VM, and dbs-cli with it.
+fn stop_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGINT);
+    signals
+}
+
+/// Block the stop signals in the calling thread, and in the threads and
+/// processes it creates later, so that they are only taken by `sigwait`.
+///
+/// This must run before any thread is spawned. A blocked signal is never
+/// ignored, which the jailed VMM relies on: as the init of its pid namespace,
+/// it would otherwise ignore the signals it has no handler for.
+pub fn block_stop_signals() -> Result<()> {
+    stop_signals()
+        .thread_block()
+        .context("Failed to block the stop signals")
+}
+
+/// Send the exit code of dbs-cli for the first stop signal received, so the
+/// main thread stops the VM and cleans up as when the guest stops it.
+pub fn handle_stop_signals(to_main: Sender<Result<i32>>) -> Result<()> {
+    thread::Builder::new()
+        .name("signal".to_owned())
+        .spawn(move || {
+            if let Ok(signal) = stop_signals().wait() {
+                let _ = to_main.send(Ok(exit_code::KILLED_BY_SIGNAL + signal as i32));
+            }
+        })
+        .context("Failed to spawn the signal thread")?;
+    Ok(())
+}
+
+/// Block `SIGCHLD` as well, for `wait_child`. This must run before the child
+/// is forked, so its exit is not missed.
+pub fn block_child_signal() -> Result<()> {
+    child_signals()
+        .thread_block()
+        .context("Failed to block SIGCHLD")
+}
+
+/// Undo `block_child_signal` in the child.
+pub fn unblock_child_signal() -> Result<()> {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGCHLD);
+    signals
+        .thread_unblock()
+        .context("Failed to unblock SIGCHLD")
+}
+
+/// Wait for the child to exit, and forward it the stop signals in the
+/// meantime. This runs in the parent of the jailed VMM, which cannot spawn
+/// threads once it has created a pid namespace.
+pub fn wait_child(child: Pid) -> Result<WaitStatus> {
+    let signals = child_signals();
+    loop {
+        match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) => {}
+            status => return status.context("Failed to wait for the jailed VMM"),
+        }
+        // SIGCHLD stays pending while it is blocked, so an exit right after
+        // waitpid still ends the wait
+        match signals.wait().context("Failed to wait for a signal")? {
+            Signal::SIGCHLD => {}
+            signal => {
+                let _ = kill(child, signal);
+            }
+        }
+    }
+}
+
+fn child_signals() -> SigSet {
+    let mut signals = stop_signals();
+    signals.add(Signal::SIGCHLD);
+    signals
+}
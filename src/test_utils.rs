@@ -0,0 +1,50 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fixtures shared by the unit tests.
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use vmm_sys_util::tempdir::TempDir;
+
+/// A temporary directory of test images, removed when dropped.
+pub struct ImageDir {
+    dir: TempDir,
+}
+
+impl ImageDir {
+    pub fn new() -> Self {
+        let prefix = std::env::temp_dir().join("dbs-cli-test-");
+        ImageDir {
+            dir: TempDir::new_with_prefix(prefix).unwrap(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.as_path()
+    }
+
+    /// Write the image `name` holding `bytes`.
+    pub fn image(&self, name: &str, bytes: &[u8]) -> PathBuf {
+        self.sparse_image(name, bytes.len() as u64, &[(0, bytes)])
+    }
+
+    /// Write a sparse image `name` of `len` bytes holding `chunks` at their
+    /// offsets.
+    pub fn sparse_image(&self, name: &str, len: u64, chunks: &[(u64, &[u8])]) -> PathBuf {
+        let path = self.path().join(name);
+        let file = File::create(&path).unwrap();
+        file.set_len(len).unwrap();
+        for (offset, bytes) in chunks {
+            file.write_all_at(bytes, *offset).unwrap();
+        }
+        path
+    }
+}
+
+/// The message of the error `result` should be.
+pub fn error_message<T: std::fmt::Debug>(result: anyhow::Result<T>) -> String {
+    format!("{:#}", result.unwrap_err())
+}
@@ -0,0 +1,409 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::affinity::{available_cpus, CpuAffinity};
+use crate::cgroup::CgroupLimits;
+use crate::cli_instance::CliInstance;
+use crate::ephemeral::SCRATCH_DRIVE_ID;
+use crate::error::Error;
+use crate::kernel_cmdline::KernelCmdline;
+use crate::kernel_image::detect_format;
+use crate::memory::check_hugepages;
+use crate::numa::NumaPolicy;
+use crate::parser::args::{MemType, SeccompLevel, VpmuFeature};
+use crate::parser::DBSArgs;
+use crate::seccomp::build_filters;
+
+/// Check the whole VM specification, and that the host can run it, before
+/// anything is sent to the VMM. Returns the kernel command line built on the
+/// way, for the VMM.
+///
+/// Every problem found is reported in the returned error, not only the first one.
+pub fn validate_args(args: &DBSArgs) -> Result<KernelCmdline> {
+    let (mut errors, kernel_cmdline) = spec_errors(args);
+    errors.extend(check_host(args));
+    report(errors, kernel_cmdline)
+}
+
+/// Check the VM specification alone, e.g. for `create --dry-run` on another
+/// host than the one running the VM.
+pub fn validate_spec(args: &DBSArgs) -> Result<KernelCmdline> {
+    let (errors, kernel_cmdline) = spec_errors(args);
+    report(errors, kernel_cmdline)
+}
+
+/// The problems of the host that prevent it from running the VM: the
+/// privileges of dbs-cli, the PMU, the free hugepages, and the CPUs and NUMA
+/// nodes available.
+pub fn check_host(args: &DBSArgs) -> Vec<String> {
+    let mut errors = vec![];
+
+    check_host_cpu(args, &mut errors);
+    check_host_memory(args, &mut errors);
+    check_host_jail(args, &mut errors);
+    check_host_affinity(args, &mut errors);
+    check_host_numa(args, &mut errors);
+
+    errors
+}
+
+fn spec_errors(args: &DBSArgs) -> (Vec<String>, Option<KernelCmdline>) {
+    let mut errors = vec![];
+
+    check_cpu(args, &mut errors);
+    check_memory(args, &mut errors);
+    let kernel_cmdline = check_boot_source(args, &mut errors);
+    check_drives(args, &mut errors);
+    check_seccomp(args, &mut errors);
+    check_jail(args, &mut errors);
+    check_cgroup(args, &mut errors);
+    check_affinity(args, &mut errors);
+    check_numa(args, &mut errors);
+    check_daemon(args, &mut errors);
+
+    (errors, kernel_cmdline)
+}
+
+fn report(errors: Vec<String>, kernel_cmdline: Option<KernelCmdline>) -> Result<KernelCmdline> {
+    match kernel_cmdline {
+        Some(kernel_cmdline) if errors.is_empty() => Ok(kernel_cmdline),
+        _ => Err(Error::Validation(format!(
+            "invalid VM specification:\n  - {}",
+            errors.join("\n  - ")
+        ))
+        .into()),
+    }
+}
+
+fn check_cpu(args: &DBSArgs, errors: &mut Vec<String>) {
+    let create_args = &args.create_args;
+    if create_args.vcpu == 0 {
+        errors.push(String::from("vcpu should be at least 1"));
+    }
+    if create_args.vcpu > create_args.max_vcpu {
+        errors.push(format!(
+            "vcpu ({}) should not be greater than max_vcpu ({})",
+            create_args.vcpu, create_args.max_vcpu
+        ));
+    }
+
+    let topology = &create_args.cpu_topology;
+    let capacity = topology.threads_per_core as usize
+        * topology.cores_per_die as usize
+        * topology.dies_per_socket as usize
+        * topology.sockets as usize;
+    if capacity < create_args.max_vcpu as usize {
+        errors.push(format!(
+            "the cpu topology ({} threads per core x {} cores per die x {} dies per socket x {} sockets) can only hold {} vcpus, but max_vcpu is {}",
+            topology.threads_per_core,
+            topology.cores_per_die,
+            topology.dies_per_socket,
+            topology.sockets,
+            capacity,
+            create_args.max_vcpu
+        ));
+    }
+}
+
+fn check_host_cpu(args: &DBSArgs, errors: &mut Vec<String>) {
+    let vpmu_feature = args.create_args.vpmu_feature;
+    if vpmu_feature != VpmuFeature::Disabled {
+        if let Err(e) = check_host_pmu() {
+            errors.push(format!(
+                "vpmu_feature {vpmu_feature:?} is not supported by the host: {e}"
+            ));
+        }
+    }
+}
+
+/// Check that the host has a PMU that KVM can virtualize.
+#[cfg(target_arch = "x86_64")]
+fn check_host_pmu() -> std::result::Result<(), String> {
+    let cpuinfo = std::fs::read_to_string("/proc/cpuinfo").map_err(|e| e.to_string())?;
+    let has_perfmon = cpuinfo
+        .lines()
+        .filter(|line| line.starts_with("flags"))
+        .any(|line| line.split_whitespace().any(|flag| flag == "arch_perfmon"));
+    if !has_perfmon {
+        return Err(String::from("the cpu has no architectural perfmon"));
+    }
+
+    // the parameter only exists on recent kernels, where the pmu can be disabled for guests
+    if let Ok(enable_pmu) = std::fs::read_to_string("/sys/module/kvm/parameters/enable_pmu") {
+        if enable_pmu.trim() == "N" {
+            return Err(String::from("the pmu is disabled by kvm.enable_pmu"));
+        }
+    }
+    Ok(())
+}
+
+/// Check that the host has a PMU that KVM can virtualize.
+#[cfg(target_arch = "aarch64")]
+fn check_host_pmu() -> std::result::Result<(), String> {
+    let devices = std::fs::read_dir("/sys/bus/event_source/devices").map_err(|e| e.to_string())?;
+    let has_pmu = devices
+        .filter_map(|device| device.ok())
+        .any(|device| device.file_name().to_string_lossy().starts_with("armv8"));
+    if !has_pmu {
+        return Err(String::from("no armv8 pmu driver is loaded"));
+    }
+    Ok(())
+}
+
+fn check_memory(args: &DBSArgs, errors: &mut Vec<String>) {
+    let create_args = &args.create_args;
+    if create_args.mem_size == 0 {
+        errors.push(String::from("mem_size should be greater than 0"));
+    }
+    if create_args.mem_type == MemType::Hugetlbfs && create_args.mem_file_path.is_empty() {
+        errors.push(String::from(
+            "mem_file_path should be set when mem_type is hugetlbfs",
+        ));
+    }
+}
+
+fn check_host_memory(args: &DBSArgs, errors: &mut Vec<String>) {
+    let create_args = &args.create_args;
+    if create_args.mem_type == MemType::Hugetlbfs && !create_args.mem_file_path.is_empty() {
+        if let Err(e) = check_hugepages(args) {
+            errors.push(format!("{e:#}"));
+        }
+    }
+}
+
+/// Returns the kernel command line, `None` if it is invalid.
+fn check_boot_source(args: &DBSArgs, errors: &mut Vec<String>) -> Option<KernelCmdline> {
+    match &args.boot_args.kernel_path {
+        Some(kernel_path) => {
+            if check_file(kernel_path, "kernel", false, errors) {
+                if let Err(e) = detect_format(kernel_path) {
+                    errors.push(format!("the kernel file {kernel_path:?} is invalid: {e}"));
+                }
+            }
+        }
+        None => errors.push(String::from("kernel_path should be set")),
+    }
+    if let Some(initrd_path) = &args.boot_args.initrd_path {
+        check_file(initrd_path, "initrd", false, errors);
+    }
+    KernelCmdline::build(args)
+        .map_err(|e| errors.push(e.to_string()))
+        .ok()
+}
+
+fn check_drives(args: &DBSArgs, errors: &mut Vec<String>) {
+    let block_devices = CliInstance::block_devices(args);
+
+    // a VM booting from an initramfs alone needs no root device
+    if args.boot_args.initrd_path.is_none() && !block_devices.iter().any(|info| info.is_root_device)
+    {
+        errors.push(String::from(
+            "a root device should be given with rootfs or a drive with root=true, or an initrd with initrd_path",
+        ));
+    }
+
+    let root_drives: Vec<&str> = block_devices
+        .iter()
+        .filter(|info| info.is_root_device)
+        .map(|info| info.drive_id.as_str())
+        .collect();
+    if root_drives.len() > 1 {
+        errors.push(format!(
+            "only one drive can be the root device, but {root_drives:?} are"
+        ));
+    }
+
+    let rootfs_args = &args.boot_args.rootfs_args;
+    if (rootfs_args.ephemeral || rootfs_args.scratch_size > 0) && rootfs_args.rootfs.is_none() {
+        errors.push(String::from(
+            "rootfs should be set for an ephemeral rootfs or a scratch drive",
+        ));
+    }
+    if let Some(ephemeral_dir) = &rootfs_args.ephemeral_dir {
+        if !Path::new(ephemeral_dir).is_dir() {
+            errors.push(format!(
+                "the ephemeral directory {ephemeral_dir:?} is not a directory"
+            ));
+        }
+    }
+    if rootfs_args.scratch_size > 0
+        && block_devices
+            .iter()
+            .any(|info| info.drive_id == SCRATCH_DRIVE_ID)
+    {
+        errors.push(format!(
+            "drive id {SCRATCH_DRIVE_ID:?} is reserved for the scratch drive"
+        ));
+    }
+
+    let mut drive_ids = HashSet::new();
+    for info in block_devices.iter() {
+        // an ephemeral rootfs is only read, the VM writes to its clone
+        let is_ephemeral = rootfs_args.ephemeral && info.drive_id == "rootfs";
+        if !drive_ids.insert(info.drive_id.as_str()) {
+            errors.push(format!(
+                "drive id {:?} is used by more than one drive",
+                info.drive_id
+            ));
+        }
+        check_file(
+            &info.path_on_host,
+            &format!("drive {:?}", info.drive_id),
+            !info.is_read_only && !is_ephemeral,
+            errors,
+        );
+    }
+}
+
+fn check_seccomp(args: &DBSArgs, errors: &mut Vec<String>) {
+    let create_args = &args.create_args;
+    if create_args.seccomp_policy.is_some() && create_args.seccomp_level == SeccompLevel::None {
+        errors.push(String::from(
+            "seccomp_policy has no effect when seccomp_level is none, use log or kill",
+        ));
+    }
+    if let Err(e) = build_filters(args) {
+        errors.push(format!("{e:#}"));
+    }
+}
+
+fn check_jail(args: &DBSArgs, errors: &mut Vec<String>) {
+    let create_args = &args.create_args;
+    let chroot = match &create_args.chroot {
+        Some(chroot) => chroot,
+        None => {
+            if create_args.uid.is_some() || create_args.gid.is_some() {
+                errors.push(String::from("uid and gid need chroot"));
+            }
+            return;
+        }
+    };
+
+    if create_args.uid.is_some() != create_args.gid.is_some() {
+        errors.push(String::from("uid and gid should be given together"));
+    }
+    if !Path::new(chroot).is_dir() {
+        errors.push(format!("the chroot {chroot:?} is not a directory"));
+    }
+    // the tap devices of the host are not in the network namespace of the jail
+    if !create_args.net.is_empty() {
+        errors.push(String::from(
+            "virtio-net devices are not supported with chroot",
+        ));
+    }
+    if !create_args.mem_file_path.is_empty() {
+        errors.push(String::from("mem_file_path is not supported with chroot"));
+    }
+}
+
+fn check_host_jail(args: &DBSArgs, errors: &mut Vec<String>) {
+    if args.create_args.chroot.is_some() && !nix::unistd::geteuid().is_root() {
+        errors.push(String::from(
+            "dbs-cli should run as root to create the namespaces of the chroot",
+        ));
+    }
+}
+
+fn check_cgroup(args: &DBSArgs, errors: &mut Vec<String>) {
+    if args.create_args.cgroup.is_none() && !CgroupLimits::from_args(args).is_empty() {
+        errors.push(String::from(
+            "cpu_max, memory_max, io_max and pids_max need cgroup",
+        ));
+    }
+}
+
+fn check_affinity(args: &DBSArgs, errors: &mut Vec<String>) {
+    let affinity = match CpuAffinity::from_args(args) {
+        Ok(affinity) => affinity,
+        Err(e) => {
+            errors.push(format!("{e:#}"));
+            return;
+        }
+    };
+
+    let max_vcpu = args.create_args.max_vcpu;
+    for vcpu_id in affinity.vcpu_ids().filter(|vcpu_id| *vcpu_id >= max_vcpu) {
+        errors.push(format!(
+            "vcpu {vcpu_id} of vcpu_affinity does not exist, the vcpu ids are below max_vcpu ({max_vcpu})"
+        ));
+    }
+}
+
+fn check_host_affinity(args: &DBSArgs, errors: &mut Vec<String>) {
+    // an invalid affinity is reported by check_affinity
+    let affinity = match CpuAffinity::from_args(args) {
+        Ok(affinity) => affinity,
+        Err(_) => return,
+    };
+    let mut cpus: Vec<usize> = affinity.host_cpus().collect();
+    if cpus.is_empty() {
+        return;
+    }
+    let available = match available_cpus() {
+        Ok(available) => available,
+        Err(e) => {
+            errors.push(format!("{e:#}"));
+            return;
+        }
+    };
+    cpus.sort_unstable();
+    cpus.dedup();
+    for cpu in cpus {
+        if !available.is_set(cpu).unwrap_or(false) {
+            errors.push(format!("host CPU {cpu} is not available to dbs-cli"));
+        }
+    }
+}
+
+fn check_numa(args: &DBSArgs, errors: &mut Vec<String>) {
+    if let Err(e) = NumaPolicy::check_args(args) {
+        errors.push(format!("{e:#}"));
+    }
+}
+
+fn check_host_numa(args: &DBSArgs, errors: &mut Vec<String>) {
+    // an invalid policy is reported by check_numa
+    if NumaPolicy::check_args(args).is_ok() {
+        if let Err(e) = NumaPolicy::from_args(args) {
+            errors.push(format!("{e:#}"));
+        }
+    }
+}
+
+fn check_daemon(args: &DBSArgs, errors: &mut Vec<String>) {
+    // the stdio console would be lost with the terminal
+    if args.create_args.daemon && args.create_args.serial_path == "stdio" {
+        errors.push(String::from("daemon needs a serial_path other than stdio"));
+    }
+}
+
+/// Make sure `path` is a file that can be opened for reading, and for writing
+/// too if `writable` is set. Returns whether it is.
+fn check_file<P: AsRef<Path>>(
+    path: P,
+    name: &str,
+    writable: bool,
+    errors: &mut Vec<String>,
+) -> bool {
+    let path = path.as_ref();
+    if !path.exists() {
+        errors.push(format!("the {name} file {path:?} does not exist"));
+        return false;
+    }
+    if path.is_dir() {
+        errors.push(format!("the {name} file {path:?} is a directory"));
+        return false;
+    }
+    if let Err(e) = OpenOptions::new().read(true).write(writable).open(path) {
+        let access = if writable { "read and written" } else { "read" };
+        errors.push(format!("the {name} file {path:?} cannot be {access}: {e}"));
+        return false;
+    }
+    true
+}
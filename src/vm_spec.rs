@@ -0,0 +1,123 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use anyhow::{anyhow, Result};
+use serde_derive::Serialize;
+
+use dragonball::{
+    api::v1::{
+        BlockDeviceConfigInfo, BootSourceConfig, FsDeviceConfigInfo, VirtioNetDeviceConfigInfo,
+        VsockDeviceConfigInfo,
+    },
+    vm::{CpuTopology, VmConfigInfo},
+};
+
+use crate::cli_instance::CliInstance;
+use crate::kernel_cmdline::KernelCmdline;
+use crate::parser::DBSArgs;
+
+/// Everything sent to the VMM to create a VM, resolved from the arguments.
+#[derive(Clone, Debug, Serialize)]
+pub struct VmSpec {
+    pub vm_config: VmConfigInfo,
+    pub boot_source: BootSourceConfig,
+    pub block_devices: Vec<BlockDeviceConfigInfo>,
+    pub vsock: Option<VsockDeviceConfigInfo>,
+    pub net: Vec<VirtioNetDeviceConfigInfo>,
+    pub fs: Vec<FsDeviceConfigInfo>,
+    /// Non-fatal problems found while building the spec
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+}
+
+impl VmSpec {
+    /// Build the VM spec from the arguments, and the kernel command line
+    /// built from them when they were validated.
+    ///
+    /// The vsock guest cid is 0 when it is not given, as it is only chosen
+    /// when the VM is created.
+    pub fn build(args: &DBSArgs, kernel_cmdline: KernelCmdline) -> Result<Self> {
+        let kernel_path = args
+            .boot_args
+            .kernel_path
+            .clone()
+            .ok_or_else(|| anyhow!("kernel path cannot be None when creating the VM"))?;
+
+        // as in crate `dragonball` serial_path will be assigned with a default value,
+        // we need a special token to enable the stdio console.
+        let serial_path = if args.create_args.serial_path != "stdio" {
+            Some(args.create_args.serial_path.clone())
+        } else {
+            None
+        };
+
+        // configuration
+        let vm_config = VmConfigInfo {
+            vcpu_count: args.create_args.vcpu,
+            max_vcpu_count: args.create_args.max_vcpu,
+            cpu_pm: args.create_args.cpu_pm.as_str().to_string(),
+            cpu_topology: CpuTopology {
+                threads_per_core: args.create_args.cpu_topology.threads_per_core,
+                cores_per_die: args.create_args.cpu_topology.cores_per_die,
+                dies_per_socket: args.create_args.cpu_topology.dies_per_socket,
+                sockets: args.create_args.cpu_topology.sockets,
+            },
+            vpmu_feature: args.create_args.vpmu_feature.level(),
+            mem_type: args.create_args.mem_type.as_str().to_string(),
+            mem_file_path: args.create_args.mem_file_path.clone(),
+            mem_size_mib: args.create_args.mem_size,
+            serial_path,
+        };
+
+        // boot source
+        let boot_source = BootSourceConfig {
+            kernel_path,
+            initrd_path: args.boot_args.initrd_path.clone(),
+            boot_args: Some(kernel_cmdline.cmdline),
+        };
+
+        let vsock = args
+            .create_args
+            .vsock
+            .as_ref()
+            .map(|vsock| VsockDeviceConfigInfo {
+                guest_cid: vsock.cid.unwrap_or(0),
+                uds_path: Some(vsock.path.clone()),
+                ..VsockDeviceConfigInfo::default()
+            });
+
+        let net = args
+            .create_args
+            .net
+            .iter()
+            .map(|net| VirtioNetDeviceConfigInfo {
+                iface_id: net.id.clone(),
+                host_dev_name: net.host_dev_name.clone(),
+                ..VirtioNetDeviceConfigInfo::default()
+            })
+            .collect();
+
+        let fs = args
+            .create_args
+            .fs
+            .iter()
+            .map(|fs| FsDeviceConfigInfo {
+                tag: fs.tag.clone(),
+                sock_path: fs.sock_path.clone(),
+                mode: String::from("vhostuser"),
+                ..FsDeviceConfigInfo::default()
+            })
+            .collect();
+
+        Ok(VmSpec {
+            vm_config,
+            boot_source,
+            // rootfs and extra drives
+            block_devices: CliInstance::block_devices(args),
+            vsock,
+            net,
+            fs,
+            warnings: kernel_cmdline.warnings,
+        })
+    }
+}
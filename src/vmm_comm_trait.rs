@@ -4,10 +4,12 @@ use vmm_sys_util::eventfd::EventFd;
 use anyhow::{anyhow, Context, Result};
 use std::sync::{Arc, Mutex};
 
+use crate::error::Error;
+
 use dragonball::{
     api::v1::{
-        BlockDeviceConfigInfo, BootSourceConfig, VmmAction, VmmActionError, VmmData, VmmRequest,
-        VmmResponse, VsockDeviceConfigInfo,
+        BlockDeviceConfigInfo, BootSourceConfig, FsDeviceConfigInfo, VirtioNetDeviceConfigInfo,
+        VmmAction, VmmActionError, VmmData, VmmRequest, VmmResponse, VsockDeviceConfigInfo,
     },
     vcpu::VcpuResizeInfo,
     vm::VmConfigInfo,
@@ -25,66 +27,53 @@ pub trait VMMComm {
     fn get_from_vmm(&self) -> Option<Arc<Mutex<Receiver<VmmResponse>>>>;
     fn get_to_vmm_fd(&self) -> &EventFd;
 
-    fn handle_request(&self, req: Request) -> Result<VmmData> {
+    fn handle_request(&self, req: Request) -> std::result::Result<VmmData, Error> {
         let Request::Sync(vmm_action) = req;
-        match self.send_request(vmm_action) {
-            Ok(vmm_outcome) => match *vmm_outcome {
-                Ok(vmm_data) => Ok(vmm_data),
-                Err(vmm_action_error) => Err(anyhow!("vmm action error: {:?}", vmm_action_error)),
-            },
-            Err(e) => Err(e),
+        match *self.send_request(vmm_action)? {
+            Ok(vmm_data) => Ok(vmm_data),
+            Err(vmm_action_error) => Err(vmm_action_error.into()),
         }
     }
 
-    fn send_request(&self, vmm_action: VmmAction) -> Result<VmmResponse> {
+    fn send_request(&self, vmm_action: VmmAction) -> std::result::Result<VmmResponse, Error> {
+        // the set_cfg and api_server threads share the channels, the lock is
+        // held for the whole exchange so each one receives its own response
+        let from_vmm = self
+            .get_from_vmm()
+            .ok_or_else(|| Error::Transport(String::from("from_vmm is None")))?;
+        let from_vmm = from_vmm.lock().unwrap();
+
         if let Some(to_vmm) = self.get_to_vmm() {
-            to_vmm
-                .send(Box::new(vmm_action.clone()))
-                .with_context(|| format!("Failed to send {vmm_action:?} via channel "))?;
+            to_vmm.send(Box::new(vmm_action.clone())).map_err(|e| {
+                Error::Transport(format!("Failed to send {vmm_action:?} via channel: {e}"))
+            })?;
         } else {
-            return Err(anyhow!("to_vmm is None"));
+            return Err(Error::Transport(String::from("to_vmm is None")));
         }
 
         //notify vmm action
         if let Err(e) = self.get_to_vmm_fd().write(1) {
-            return Err(anyhow!("failed to notify vmm: {}", e));
+            return Err(Error::Transport(format!("failed to notify vmm: {e}")));
         }
 
-        if let Some(from_vmm) = self.get_from_vmm().as_ref() {
-            match from_vmm.lock().unwrap().recv() {
-                Err(e) => Err(anyhow!("vmm recv err: {}", e)),
-                Ok(vmm_outcome) => Ok(vmm_outcome),
-            }
-        } else {
-            Err(anyhow!("from_vmm is None"))
-        }
+        from_vmm
+            .recv()
+            .map_err(|e| Error::Transport(format!("vmm recv err: {e}")))
     }
-    fn handle_request_with_retry(&self, req: Request) -> Result<VmmData> {
+    fn handle_request_with_retry(&self, req: Request) -> std::result::Result<VmmData, Error> {
         let Request::Sync(vmm_action) = req;
         for _ in 0..REQUEST_RETRY {
-            match self.send_request(vmm_action.clone()) {
-                Ok(vmm_outcome) => match *vmm_outcome {
-                    Ok(vmm_data) => {
-                        return Ok(vmm_data);
-                    }
-                    Err(vmm_action_error) => {
-                        if let VmmActionError::UpcallServerNotReady = vmm_action_error {
-                            std::thread::sleep(std::time::Duration::from_millis(10));
-                            continue;
-                        } else {
-                            return Err(vmm_action_error.into());
-                        }
-                    }
-                },
-                Err(err) => {
-                    return Err(err);
+            match self.handle_request(Request::Sync(vmm_action.clone())) {
+                Err(Error::VmmAction(VmmActionError::UpcallServerNotReady)) => {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                    continue;
                 }
+                result => return result,
             }
         }
-        Err(anyhow::anyhow!(
-            "After {} attempts, it still doesn't work.",
-            REQUEST_RETRY
-        ))
+        Err(Error::Timeout(format!(
+            "the upcall server of the VM is still not ready after {REQUEST_RETRY} attempts"
+        )))
     }
 
     fn put_boot_source(&self, boot_source_cfg: BootSourceConfig) -> Result<()> {
@@ -109,6 +98,16 @@ pub trait VMMComm {
         Ok(())
     }
 
+    fn get_vm_configuration(&self) -> Result<VmConfigInfo> {
+        match self
+            .handle_request(Request::Sync(VmmAction::GetVmConfiguration))
+            .context("Failed to get vm configuration")?
+        {
+            VmmData::MachineConfiguration(vm_config) => Ok(*vm_config),
+            vmm_data => Err(anyhow!("unexpected vmm data {:?}", vmm_data)),
+        }
+    }
+
     fn set_vm_configuration(&self, vm_config: VmConfigInfo) -> Result<()> {
         self.handle_request(Request::Sync(VmmAction::SetVmConfiguration(
             vm_config.clone(),
@@ -125,6 +124,20 @@ pub trait VMMComm {
         Ok(())
     }
 
+    fn insert_network_device(&self, net_cfg: VirtioNetDeviceConfigInfo) -> Result<()> {
+        self.handle_request_with_retry(Request::Sync(VmmAction::InsertNetworkDevice(
+            net_cfg.clone(),
+        )))
+        .with_context(|| format!("Failed to insert network device {net_cfg:?}"))?;
+        Ok(())
+    }
+
+    fn insert_fs_device(&self, fs_cfg: FsDeviceConfigInfo) -> Result<()> {
+        self.handle_request(Request::Sync(VmmAction::InsertFsDevice(fs_cfg.clone())))
+            .with_context(|| format!("Failed to insert fs device {fs_cfg:?}"))?;
+        Ok(())
+    }
+
     fn resize_vcpu(&self, resize_vcpu_cfg: VcpuResizeInfo) -> Result<()> {
         self.handle_request(Request::Sync(VmmAction::ResizeVcpu(
             resize_vcpu_cfg.clone(),
@@ -0,0 +1,166 @@
+// Copyright (C) 2022 Alibaba Cloud. All rights reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fs::{Metadata, Permissions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use nix::unistd::geteuid;
+
+use crate::file_lock::{lock_holder, FileLock};
+use crate::parser::RUN_DIR;
+
+/// CIDs 0 and 1 are reserved, and 2 is the well-known CID of the host.
+const RESERVED_CIDS: [u32; 3] = [0, 1, 2];
+/// VMADDR_CID_ANY
+const CID_ANY: u32 = u32::MAX;
+/// The first CID tried when no CID is given.
+const FIRST_GUEST_CID: u32 = 3;
+/// How many CIDs are tried before giving up when no CID is given.
+const CID_SEARCH_LIMIT: u32 = 4096;
+/// The host-wide registry is writable by all the users, and sticky.
+const SHARED_DIR_MODE: u32 = 0o1777;
+
+/// A guest CID reserved for this dbs-cli instance.
+///
+/// The reservation is an exclusive `flock` on `<registry>/<cid>.lock`, so it
+/// is released by the kernel when the process exits, however it exits. The
+/// lock file is removed when the lease is dropped.
+pub struct CidLease {
+    pub cid: u32,
+    _lock: FileLock,
+}
+
+/// Reserve `cid`, or the first free CID if it is `None`.
+pub fn acquire_cid(cid: Option<u32>) -> Result<CidLease> {
+    let registry = registry_dir()?;
+    match cid {
+        Some(cid) => {
+            check_cid(cid)?;
+            try_lock_cid(&registry, cid)?.ok_or_else(|| {
+                let holder = lock_holder(&cid_lock_path(&registry, cid))
+                    .map(|holder| holder.pid.to_string())
+                    .unwrap_or_else(|| String::from("unknown"));
+                anyhow!("vsock guest cid {cid} is already used by the dbs-cli instance with pid {holder}")
+            })
+        }
+        None => {
+            for cid in FIRST_GUEST_CID..FIRST_GUEST_CID + CID_SEARCH_LIMIT {
+                if let Some(lease) = try_lock_cid(&registry, cid)? {
+                    return Ok(lease);
+                }
+            }
+            Err(anyhow!("no free vsock guest cid found"))
+        }
+    }
+}
+
+fn check_cid(cid: u32) -> Result<()> {
+    if RESERVED_CIDS.contains(&cid) || cid == CID_ANY {
+        return Err(anyhow!(
+            "vsock guest cid {cid} is reserved, use a cid greater than 2"
+        ));
+    }
+    Ok(())
+}
+
+/// The directory of the CID lock files: the host-wide `<RUN_DIR>/vsock`,
+/// shared by all the users. When it does not exist and cannot be created,
+/// i.e. for a user other than root, it is the per-user
+/// `$XDG_RUNTIME_DIR/dbs-cli/vsock`, or `<tmp>/dbs-cli-<uid>/vsock` without
+/// it, whose CIDs are only refused between the instances of that user.
+fn registry_dir() -> Result<PathBuf> {
+    let registry = Path::new(RUN_DIR).join("vsock");
+    let uid = geteuid();
+    if uid.is_root() {
+        shared_dir(&registry)?;
+        return Ok(registry);
+    }
+    match std::fs::symlink_metadata(&registry) {
+        Ok(metadata) => {
+            check_shared_dir(&registry, &metadata)?;
+            return Ok(registry);
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to stat {registry:?}")),
+    }
+
+    eprintln!(
+        "dbs-cli: warning: the host-wide vsock cid registry {registry:?} does not exist, the guest cid is only checked against the VMs of uid {uid}"
+    );
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("dbs-cli"),
+        None => {
+            let dir = std::env::temp_dir().join(format!("dbs-cli-{uid}"));
+            private_dir(&dir, uid.as_raw())?;
+            dir
+        }
+    };
+    let registry = dir.join("vsock");
+    std::fs::create_dir_all(&registry)
+        .with_context(|| format!("Failed to create vsock cid registry {registry:?}"))?;
+    Ok(registry)
+}
+
+/// Create the host-wide registry `dir`, writable by all the users and sticky,
+/// so that they cannot remove the lock files of each other.
+fn shared_dir(dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create vsock cid registry {dir:?}"))?;
+    std::fs::set_permissions(dir, Permissions::from_mode(SHARED_DIR_MODE))
+        .with_context(|| format!("Failed to change the mode of {dir:?}"))
+}
+
+/// Check that the host-wide registry was created by root, and is sticky.
+fn check_shared_dir(dir: &Path, metadata: &Metadata) -> Result<()> {
+    if !metadata.is_dir() || metadata.uid() != 0 || metadata.mode() & 0o7777 != SHARED_DIR_MODE {
+        return Err(anyhow!(
+            "{dir:?} must be a directory owned by root with mode {SHARED_DIR_MODE:o}"
+        ));
+    }
+    Ok(())
+}
+
+/// Create the per-user directory `dir` in the shared temporary directory,
+/// or check that the existing one belongs to `uid` only.
+fn private_dir(dir: &Path, uid: u32) -> Result<()> {
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to create {dir:?}")),
+    }
+    let metadata =
+        std::fs::symlink_metadata(dir).with_context(|| format!("Failed to stat {dir:?}"))?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(anyhow!(
+            "{dir:?} must be a directory owned by uid {uid} and only accessible to it"
+        ));
+    }
+    Ok(())
+}
+
+fn cid_lock_path(registry: &Path, cid: u32) -> PathBuf {
+    registry.join(format!("{cid}.lock"))
+}
+
+/// Lock the registry file of `cid`, returns `None` if the CID is in use.
+fn try_lock_cid(registry: &Path, cid: u32) -> Result<Option<CidLease>> {
+    let path = cid_lock_path(registry, cid);
+    let lock = match FileLock::create(&path)
+        .with_context(|| format!("Failed to lock vsock guest cid {cid}"))?
+    {
+        Some(lock) => lock,
+        None => return Ok(None),
+    };
+
+    // record the holder for the users looking at the registry, but a lock
+    // file left by another user is read-only
+    let mut file = lock.file();
+    let _ = file
+        .set_len(0)
+        .and_then(|()| write!(file, "{}", std::process::id()));
+
+    Ok(Some(CidLease { cid, _lock: lock }))
+}